@@ -2,9 +2,11 @@ set -euxo pipefail
 
 main() {
     cargo check --target $TARGET
+    cargo check --target $TARGET --no-default-features
 
     cargo test --target $TARGET
     cargo test --target $TARGET --release
+    cargo test --target $TARGET --features testutil
 }
 
 main
@@ -0,0 +1,115 @@
+//! Comparison of decoded traces
+
+use crate::Packet;
+
+/// An edit that turns the first trace into the second one
+///
+/// Indices refer to positions in the first trace, timestamp packets included.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TraceDiff {
+    /// `packet` only appears in the second trace; it goes before the packet at `index`
+    Insert {
+        /// Position in the first trace
+        index: usize,
+        /// The inserted packet
+        packet: Packet,
+    },
+    /// The packet at `index` only appears in the first trace
+    Delete {
+        /// Position in the first trace
+        index: usize,
+        /// The deleted packet
+        packet: Packet,
+    },
+    /// The packet at `index` has been replaced by `new`
+    Replace {
+        /// Position in the first trace
+        index: usize,
+        /// The packet in the first trace
+        old: Packet,
+        /// The packet in the second trace
+        new: Packet,
+    },
+}
+
+/// Compares two traces ignoring timestamps
+///
+/// Local and global timestamp packets are skipped; the rest of the packets must match in content
+/// and order. Returns the edits of a longest common subsequence diff, which is empty if the
+/// traces match. This takes `O(n * m)` time and memory so it's meant for host side regression
+/// testing of moderately sized captures.
+pub fn diff_traces<A, B>(a: A, b: B) -> Vec<TraceDiff>
+where
+    A: IntoIterator<Item = Packet>,
+    B: IntoIterator<Item = Packet>,
+{
+    let mut len = 0;
+    let a = a
+        .into_iter()
+        .enumerate()
+        .inspect(|_| len += 1)
+        .filter(|(_, packet)| !is_timestamp(packet))
+        .collect::<Vec<_>>();
+    let b = b
+        .into_iter()
+        .filter(|packet| !is_timestamp(packet))
+        .collect::<Vec<_>>();
+
+    // lcs[i][j] is the length of the longest common subsequence of `a[i..]` and `b[j..]`
+    let mut lcs = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i].1 == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut diffs = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        let (index, packet) = a[i];
+
+        if packet == b[j] {
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j + 1] == lcs[i][j] {
+            diffs.push(TraceDiff::Replace {
+                index,
+                old: packet,
+                new: b[j],
+            });
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            diffs.push(TraceDiff::Delete { index, packet });
+            i += 1;
+        } else {
+            diffs.push(TraceDiff::Insert {
+                index,
+                packet: b[j],
+            });
+            j += 1;
+        }
+    }
+
+    for &(index, packet) in &a[i..] {
+        diffs.push(TraceDiff::Delete { index, packet });
+    }
+
+    for &packet in &b[j..] {
+        diffs.push(TraceDiff::Insert { index: len, packet });
+    }
+
+    diffs
+}
+
+#[allow(clippy::match_like_matches_macro)]
+fn is_timestamp(packet: &Packet) -> bool {
+    match *packet {
+        Packet::LocalTimestamp(_) | Packet::GTS1(_) | Packet::GTS2(_) => true,
+        _ => false,
+    }
+}
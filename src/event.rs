@@ -0,0 +1,153 @@
+//! Flat projection of packets for tabular storage
+
+use crate::{packet::Function, Packet};
+
+/// A packet flattened into a uniform schema
+///
+/// Every packet maps to the same set of columns, which makes loading ITM data into databases or
+/// columnar stores trivial. Columns that don't apply to a packet are `None`; multi-byte numbers
+/// stored in `value` are little endian:
+///
+/// - `Instrumentation`: `port` (with stimulus port pages applied), `value` (payload) and `text`
+///   (payload, if it's valid UTF-8)
+/// - `LocalTimestamp`: `value` (the delta, 4 bytes)
+/// - `GTS1` / `GTS2`: `value` (the timestamp bits, 4 / 8 bytes)
+/// - `StimulusPortPage`, `Extension`: `value` (the page or extension info, 1 byte)
+/// - `EventCounter`: `value` (the counter flags, 1 byte) and `text` (the names of the wrapped
+///   counters, comma separated)
+/// - `ExceptionTrace`: `value` (the exception number, 2 bytes) and `text` ("enter", "exit" or
+///   "return")
+/// - `PeriodicPcSample`: `pc` (`None` while sleeping)
+/// - `DataTracePcValue`: `comparator` and `pc`
+/// - `DataTraceAddress`: `comparator` and `value` (the address, 2 bytes)
+/// - `DataTraceDataValue`: `comparator`, `value` and `text` ("read" or "write")
+///
+/// `offset_ns` is only set by `TraceEvent::timestamped`; packets don't carry an absolute time.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceEvent {
+    /// Time of the packet relative to the start of the trace, in nanoseconds
+    pub offset_ns: Option<u64>,
+    /// The packet type in snake case, e.g. "instrumentation"
+    pub kind: &'static str,
+    /// Stimulus port
+    pub port: Option<u16>,
+    /// DWT comparator
+    pub comparator: Option<u8>,
+    /// Program counter
+    pub pc: Option<u32>,
+    /// Raw payload or value
+    pub value: Option<Vec<u8>>,
+    /// Textual payload or description
+    pub text: Option<String>,
+}
+
+impl TraceEvent {
+    /// Projects `packet` like `From` does and sets `offset_ns`
+    ///
+    /// The offset comes from the caller, e.g. the host receive time (see
+    /// `Stream::next_with_recv_time`) or accumulated timestamp packets converted with the trace
+    /// clock frequency.
+    pub fn timestamped(packet: &Packet, offset_ns: u64) -> Self {
+        TraceEvent {
+            offset_ns: Some(offset_ns),
+            ..TraceEvent::from(packet)
+        }
+    }
+
+    fn new(kind: &'static str) -> Self {
+        TraceEvent {
+            offset_ns: None,
+            kind,
+            port: None,
+            comparator: None,
+            pc: None,
+            value: None,
+            text: None,
+        }
+    }
+}
+
+impl<'a> From<&'a Packet> for TraceEvent {
+    fn from(packet: &'a Packet) -> Self {
+        match *packet {
+            Packet::Overflow => TraceEvent::new("overflow"),
+            Packet::Synchronization(_) => TraceEvent::new("synchronization"),
+            Packet::Instrumentation(i) => TraceEvent {
+                port: Some(i.paged_port()),
+                value: Some(i.payload().to_vec()),
+                text: std::str::from_utf8(i.payload()).ok().map(str::to_owned),
+                ..TraceEvent::new("instrumentation")
+            },
+            Packet::LocalTimestamp(lt) => TraceEvent {
+                value: Some(le_bytes(u64::from(lt.delta()), 4)),
+                ..TraceEvent::new("local_timestamp")
+            },
+            Packet::GTS1(gt) => TraceEvent {
+                value: Some(le_bytes(u64::from(gt.bits()), 4)),
+                ..TraceEvent::new("gts1")
+            },
+            Packet::GTS2(gt) => TraceEvent {
+                value: Some(le_bytes(gt.bits(), 8)),
+                ..TraceEvent::new("gts2")
+            },
+            Packet::StimulusPortPage(spp) => TraceEvent {
+                value: Some(vec![spp.page()]),
+                ..TraceEvent::new("stimulus_port_page")
+            },
+            Packet::Extension(e) => TraceEvent {
+                value: Some(vec![e.info()]),
+                ..TraceEvent::new("extension")
+            },
+            Packet::EventCounter(ec) => TraceEvent {
+                value: Some(vec![ec
+                    .wrapped()
+                    .fold(0, |flags, counter| flags | 1 << counter as u8)]),
+                text: Some(
+                    ec.wrapped()
+                        .map(|counter| counter.register_name())
+                        .collect::<Vec<_>>()
+                        .join(","),
+                ),
+                ..TraceEvent::new("event_counter")
+            },
+            Packet::ExceptionTrace(et) => TraceEvent {
+                value: Some(le_bytes(u64::from(et.number()), 2)),
+                text: Some(
+                    match et.function() {
+                        Function::Enter => "enter",
+                        Function::Exit => "exit",
+                        Function::Return => "return",
+                    }
+                    .to_owned(),
+                ),
+                ..TraceEvent::new("exception_trace")
+            },
+            Packet::PeriodicPcSample(pps) => TraceEvent {
+                pc: pps.pc(),
+                ..TraceEvent::new("pc_sample")
+            },
+            Packet::DataTracePcValue(dtpv) => TraceEvent {
+                comparator: Some(dtpv.comparator()),
+                pc: Some(dtpv.pc()),
+                ..TraceEvent::new("data_trace_pc_value")
+            },
+            Packet::DataTraceAddress(dta) => TraceEvent {
+                comparator: Some(dta.comparator()),
+                value: Some(le_bytes(u64::from(dta.address()), 2)),
+                ..TraceEvent::new("data_trace_address")
+            },
+            Packet::DataTraceDataValue(dtdv) => TraceEvent {
+                comparator: Some(dtdv.comparator()),
+                value: Some(dtdv.value().to_vec()),
+                text: Some(if dtdv.write_access() { "write" } else { "read" }.to_owned()),
+                ..TraceEvent::new("data_trace_data_value")
+            },
+        }
+    }
+}
+
+fn le_bytes(value: u64, len: u8) -> Vec<u8> {
+    let mut bytes = vec![];
+    crate::push_le(&mut bytes, value, len);
+    bytes
+}
@@ -0,0 +1,85 @@
+//! Transport framing
+
+use std::io::{self, Read};
+
+/// Adapter that removes a fixed size header from each fixed size chunk of the input
+///
+/// Some probe firmwares prepend a small header (e.g. a length or sequence number) to every USB
+/// transfer. This adapter drops the first `header_len` bytes of every `chunk_size` bytes of
+/// `inner` and yields the rest, so it can be wrapped in a `Stream` directly. The last chunk may
+/// be shorter than `chunk_size`.
+#[derive(Debug)]
+pub struct StripHeaderReader<R>
+where
+    R: Read,
+{
+    chunk_size: usize,
+    header_len: usize,
+    inner: R,
+    // position within the current chunk
+    pos: usize,
+}
+
+impl<R> StripHeaderReader<R>
+where
+    R: Read,
+{
+    /// Creates a new reader that strips `header_len` bytes from every `chunk_size` bytes of
+    /// `inner`
+    ///
+    /// # Panics
+    ///
+    /// This constructor panics if `header_len` is not smaller than `chunk_size`
+    pub fn new(inner: R, chunk_size: usize, header_len: usize) -> Self {
+        assert!(
+            header_len < chunk_size,
+            "`header_len` must be smaller than `chunk_size`"
+        );
+
+        StripHeaderReader {
+            chunk_size,
+            header_len,
+            inner,
+            pos: 0,
+        }
+    }
+
+    /// Gets a reference to the underlying reader.
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Unwraps this `StripHeaderReader`, returning the underlying reader.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R> Read for StripHeaderReader<R>
+where
+    R: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // skip the header of the current chunk
+        while self.pos < self.header_len {
+            let mut header = [0; 16];
+            let len = (self.header_len - self.pos).min(header.len());
+
+            match self.inner.read(&mut header[..len])? {
+                // EOF
+                0 => return Ok(0),
+                n => self.pos += n,
+            }
+        }
+
+        let len = buf.len().min(self.chunk_size - self.pos);
+        let n = self.inner.read(&mut buf[..len])?;
+
+        self.pos += n;
+        if self.pos == self.chunk_size {
+            self.pos = 0;
+        }
+
+        Ok(n)
+    }
+}
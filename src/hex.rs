@@ -0,0 +1,126 @@
+//! Hex encoded trace logs
+
+use std::io::{self, BufRead, ErrorKind, Read};
+
+/// Adapter that reads ITM data from a hex encoded text log
+///
+/// Each line holds the bytes of the trace as pairs of hexadecimal digits, optionally separated by
+/// whitespace and optionally prefixed with `0x`. Everything following a `#` is a comment. Empty
+/// and comment-only lines are ignored. The text is decoded lazily, line by line, so this can be
+/// wrapped in a `Stream` directly:
+///
+/// ``` no_run
+/// use std::{fs::File, io::BufReader};
+///
+/// use itm::{hex::HexLineReader, Stream};
+///
+/// let file = File::open("trace.txt").unwrap();
+/// let mut stream = Stream::new(HexLineReader::new(BufReader::new(file)), false);
+/// ```
+///
+/// Malformed hex text is reported as an I/O error of kind `InvalidData`.
+#[derive(Debug)]
+pub struct HexLineReader<B>
+where
+    B: BufRead,
+{
+    // decoded bytes of the current line
+    bytes: Vec<u8>,
+    inner: B,
+    line: String,
+    // number of bytes of `bytes` that have already been read out
+    pos: usize,
+}
+
+impl<B> HexLineReader<B>
+where
+    B: BufRead,
+{
+    /// Creates a new reader that decodes the hex text read from `inner`
+    pub fn new(inner: B) -> Self {
+        HexLineReader {
+            bytes: vec![],
+            inner,
+            line: String::new(),
+            pos: 0,
+        }
+    }
+
+    /// Gets a reference to the underlying reader.
+    pub fn get_ref(&self) -> &B {
+        &self.inner
+    }
+
+    /// Unwraps this `HexLineReader`, returning the underlying reader.
+    ///
+    /// Note that any decoded data that has not been read yet is lost.
+    pub fn into_inner(self) -> B {
+        self.inner
+    }
+}
+
+impl<B> Read for HexLineReader<B>
+where
+    B: BufRead,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.bytes.len() {
+            self.bytes.clear();
+            self.line.clear();
+            self.pos = 0;
+
+            if self.inner.read_line(&mut self.line)? == 0 {
+                // EOF
+                return Ok(0);
+            }
+
+            decode_line(&self.line, &mut self.bytes)?;
+        }
+
+        let n = buf.len().min(self.bytes.len() - self.pos);
+        buf[..n].copy_from_slice(&self.bytes[self.pos..self.pos + n]);
+        self.pos += n;
+
+        Ok(n)
+    }
+}
+
+fn decode_line(line: &str, bytes: &mut Vec<u8>) -> io::Result<()> {
+    let text = match line.find('#') {
+        Some(comment) => &line[..comment],
+        None => line,
+    };
+
+    for token in text.split_whitespace() {
+        let digits = if token.starts_with("0x") || token.starts_with("0X") {
+            &token[2..]
+        } else {
+            token
+        };
+
+        // `from_str_radix` accepts a leading sign, e.g. "+f"
+        if digits.is_empty()
+            || digits.len() % 2 != 0
+            || !digits.bytes().all(|b| b.is_ascii_hexdigit())
+        {
+            return Err(invalid_hex(token));
+        }
+
+        for i in (0..digits.len()).step_by(2) {
+            let byte = digits
+                .get(i..i + 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| invalid_hex(token))?;
+            bytes.push(byte);
+        }
+    }
+
+    Ok(())
+}
+
+fn invalid_hex(token: &str) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("invalid hex token: {:?}", token),
+    )
+}
@@ -0,0 +1,70 @@
+//! Per stimulus port interpretation of instrumentation payloads
+
+use core::fmt;
+use std::collections::BTreeMap;
+
+use crate::Packet;
+
+/// A registry of payload interpreters, one per stimulus port
+///
+/// Different ports usually carry different kinds of data (e.g. text on one port, binary telemetry
+/// on another). Register an interpreter for each port of interest and feed all the decoded
+/// packets to `interpret`; the instrumentation packets are turned into values of the
+/// user-defined type `T`. Ports are identified by `Instrumentation::paged_port`, so ports above
+/// 31 can be told apart.
+pub struct Interpreters<T> {
+    interpreters: BTreeMap<u16, Interpreter<T>>,
+}
+
+type Interpreter<T> = Box<dyn Fn(&[u8]) -> T + Send + Sync>;
+
+impl<T> Interpreters<T> {
+    /// Creates an empty registry
+    pub fn new() -> Self {
+        Interpreters {
+            interpreters: BTreeMap::new(),
+        }
+    }
+
+    /// Registers the interpreter of the payloads sent over `port`
+    ///
+    /// This replaces the previous interpreter of `port`, if any.
+    pub fn register<F>(&mut self, port: u16, interpreter: F)
+    where
+        F: Fn(&[u8]) -> T + Send + Sync + 'static,
+    {
+        self.interpreters.insert(port, Box::new(interpreter));
+    }
+
+    /// Returns `true` if an interpreter has been registered for `port`
+    pub fn contains(&self, port: u16) -> bool {
+        self.interpreters.contains_key(&port)
+    }
+
+    /// Interprets the payload of an instrumentation packet
+    ///
+    /// Returns `None` for other packets and for ports without a registered interpreter.
+    pub fn interpret(&self, packet: &Packet) -> Option<T> {
+        if let Packet::Instrumentation(i) = packet {
+            self.interpreters
+                .get(&i.paged_port())
+                .map(|interpreter| interpreter(i.payload()))
+        } else {
+            None
+        }
+    }
+}
+
+impl<T> Default for Interpreters<T> {
+    fn default() -> Self {
+        Interpreters::new()
+    }
+}
+
+impl<T> fmt::Debug for Interpreters<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Interpreters")
+            .field("ports", &self.interpreters.keys())
+            .finish()
+    }
+}
@@ -1,14 +1,21 @@
 //! ITM packet parser
 //!
+//! # Thread safety
+//!
+//! `Stream` holds no shared state: it is `Send` whenever the wrapped reader is, so a
+//! `Stream<File>` can be moved into a worker thread. It's not `Sync` because the registered
+//! callbacks and observer only need to be `Send`. `Packet` and `Error` are plain data and
+//! always `Send + Sync`.
+//!
 //! # References
 //!
 //! - [ARMv7-M Architecture Reference Manual (DDI 0403E.b)][0] - Appendix D4 Debug ITM and DWT
-//! Packet Protocol
+//!   Packet Protocol
 //!
 //! [0]: https://static.docs.arm.com/ddi0403/eb/DDI0403E_B_armv7m_arm.pdf
 //!
 //! - [CoreSight Components Technical Reference Manual (DDI 0314H)][1] - Chapter 12 Instrumentation
-//! Trace Macrocell
+//!   Trace Macrocell
 //!
 //! [1]: http://infocenter.arm.com/help/topic/com.arm.doc.ddi0314h/DDI0314H_coresight_components_trm.pdf
 
@@ -16,37 +23,104 @@
 #![deny(warnings)]
 
 use core::fmt;
-use std::io::{self, ErrorKind, Read};
+use std::{
+    collections::BTreeSet,
+    io::{self, ErrorKind, Read, Write},
+    sync::mpsc::Sender,
+    time::{Duration, Instant, SystemTime},
+};
 
 use byteorder::{ByteOrder, LE};
 use either::Either;
-use thiserror::Error;
 
 use crate::packet::{
-    DataTraceAddress, DataTraceDataValue, DataTracePcValue, EventCounter, ExceptionTrace, Function,
-    Instrumentation, LocalTimestamp, PeriodicPcSample, StimulusPortPage, Synchronization, GTS1,
-    GTS2,
+    Access, DataTraceAddress, DataTraceDataValue, DataTracePcValue, EventCounter, ExceptionTrace,
+    Extension, Function, Instrumentation, LocalTimestamp, PeriodicPcSample, StimulusPortPage,
+    Synchronization, GTS1, GTS2,
 };
 
+pub mod diff;
+pub mod event;
+pub mod framing;
+pub mod hex;
+pub mod interpret;
 pub mod packet;
+pub mod profile;
+pub mod record;
 #[cfg(test)]
 mod tests;
+#[cfg(feature = "testutil")]
+pub mod testutil;
 
 /// A stream of ITM packets
 pub struct Stream<R>
 where
     R: Read,
 {
+    // whether to skip runs of zeros that don't form a Synchronization packet
+    allow_zero_padding: bool,
     // have we reached the EOF of the reader?
     at_eof: bool,
+    // maximum number of bytes to read from `reader`
+    byte_limit: Option<u64>,
+    // number of bytes read from `reader` so far
+    bytes_read: u64,
     // NOTE size is optimized for reading from `/dev/ttyUSB*`; `Read::read` usually reads in 32-byte
     // chunks
     buffer: [u8; 64],
+    // bitmask of the DWT comparators whose data trace packets are yielded
+    comparator_mask: u8,
+    // bitmask of the DWT comparators that have produced data trace packets
+    comparators: u8,
+    // whether to report exception returns as exits
+    collapse_exception_returns: bool,
+    // number of bytes discarded since the last Synchronization packet
+    discarded: usize,
+    // whether to stop at the first Overflow packet
+    error_on_overflow: bool,
+    // when the reader started returning no data
+    idle_since: Option<Instant>,
+    // how long the reader can go without producing data before `on_idle` is invoked
+    idle_timeout: Duration,
     // whether to continue reading past a (temporary) EOF condition
     keep_reading: bool,
+    // bytes of the most recently reported decoding error
+    last_error_bytes: Vec<u8>,
+    // stream offset of the most recently reported decoding error
+    last_error_offset: Option<u64>,
+    // header byte of the most recently decoded packet
+    last_header: u8,
+    // the most recently decoded packet
+    last_packet: Option<Packet>,
+    // number of bytes discarded before the last Synchronization packet
+    last_resync: usize,
     // number of read bytes in `buffer`
     len: usize,
+    // whether to accept GTS2 packets of non-standard size
+    lenient_gts2: bool,
+    // notified of every decoding event
+    observer: Option<Box<dyn Observer + Send>>,
+    // invoked when the reader stalls for `idle_timeout`
+    on_idle: Option<Box<dyn FnMut() + Send>>,
+    // invoked when a Synchronization packet follows discarded (malformed) bytes
+    on_resync: Option<Box<dyn FnMut(usize) + Send>>,
+    // number of packets decoded so far
+    packet_count: u64,
+    // current stimulus port page
+    page: u8,
+    // reports the number of bytes read
+    progress: Option<Progress>,
+    // only every `pc_sample_decimation`-th PC sample is yielded
+    pc_sample_decimation: u64,
+    // number of PC samples decoded so far
+    pc_samples: u64,
     reader: R,
+    // whether to silently skip reserved header bytes
+    skip_reserved_headers: bool,
+    // what a Synchronization packet looks like
+    sync_pattern: SyncPattern,
+    // whether to report a truncated final packet as `Error::UnexpectedEof`
+    unexpected_eof_error: bool,
 }
 
 impl<R> fmt::Debug for Stream<R>
@@ -55,10 +129,35 @@ where
 {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("Stream")
+            .field("allow_zero_padding", &self.allow_zero_padding)
             .field("at_eof", &self.at_eof)
             .field("buffer", &&self.buffer[..self.len])
+            .field("byte_limit", &self.byte_limit)
+            .field("bytes_read", &self.bytes_read)
+            .field(
+                "collapse_exception_returns",
+                &self.collapse_exception_returns,
+            )
+            .field("comparator_mask", &self.comparator_mask)
+            .field("comparators", &self.comparators)
+            .field("error_on_overflow", &self.error_on_overflow)
+            .field("idle_since", &self.idle_since)
+            .field("idle_timeout", &self.idle_timeout)
             .field("keep_reading", &self.keep_reading)
+            .field("last_error_bytes", &self.last_error_bytes)
+            .field("last_error_offset", &self.last_error_offset)
+            .field("last_header", &self.last_header)
+            .field("last_packet", &self.last_packet)
+            .field("last_resync", &self.last_resync)
+            .field("lenient_gts2", &self.lenient_gts2)
+            .field("packet_count", &self.packet_count)
+            .field("page", &self.page)
+            .field("pc_sample_decimation", &self.pc_sample_decimation)
+            .field("pc_samples", &self.pc_samples)
             .field("reader", &self.reader)
+            .field("skip_reserved_headers", &self.skip_reserved_headers)
+            .field("sync_pattern", &self.sync_pattern)
+            .field("unexpected_eof_error", &self.unexpected_eof_error)
             .finish()
     }
 }
@@ -73,14 +172,85 @@ where
     /// (temporary) EOF conditions
     pub fn new(reader: R, keep_reading: bool) -> Stream<R> {
         Stream {
+            allow_zero_padding: false,
             buffer: [0; 64],
             at_eof: false,
+            byte_limit: None,
+            bytes_read: 0,
+            collapse_exception_returns: false,
+            comparator_mask: 0xff,
+            comparators: 0,
+            discarded: 0,
+            error_on_overflow: false,
+            idle_since: None,
+            idle_timeout: Duration::from_secs(0),
             keep_reading,
+            last_error_bytes: vec![],
+            last_error_offset: None,
+            last_header: 0,
+            last_packet: None,
+            last_resync: 0,
             len: 0,
+            lenient_gts2: false,
+            observer: None,
+            on_idle: None,
+            on_resync: None,
+            packet_count: 0,
+            page: 0,
+            pc_sample_decimation: 1,
+            progress: None,
+            pc_samples: 0,
             reader,
+            skip_reserved_headers: false,
+            sync_pattern: SyncPattern::default(),
+            unexpected_eof_error: false,
         }
     }
 
+    /// Creates a stream that resumes decoding from `checkpoint`
+    ///
+    /// `reader` must start at `checkpoint.position()`, e.g. a file that has been reopened and
+    /// seeked to that offset. The options are the defaults, as with `new`; set them again through
+    /// the `set_*` methods. Offsets (`position`, `last_error_offset`) and the byte limit keep
+    /// counting from the start of the original stream.
+    pub fn from_checkpoint(reader: R, keep_reading: bool, checkpoint: &Checkpoint) -> Stream<R> {
+        let mut stream = Stream::new(reader, keep_reading);
+        stream.bytes_read = checkpoint.position;
+        stream.comparators = checkpoint.comparators;
+        stream.discarded = checkpoint.discarded;
+        stream.last_error_offset = checkpoint.last_error_offset;
+        stream.last_header = checkpoint.last_header;
+        stream.last_resync = checkpoint.last_resync;
+        stream.packet_count = checkpoint.packet_count;
+        stream.page = checkpoint.page;
+        stream.pc_samples = checkpoint.pc_samples;
+        stream
+    }
+
+    /// Creates a new stream that reads from `reader` and uses the same options as this one
+    ///
+    /// This copies `keep_reading` and every option set through the `set_*` methods, except for
+    /// the callbacks and the observer which can't be cloned. No decoding state (buffered bytes,
+    /// counters, last packet, etc.) is carried over. This is handy when processing several
+    /// captures with identical settings.
+    pub fn with_same_options<R2>(&self, reader: R2) -> Stream<R2>
+    where
+        R2: Read,
+    {
+        let mut stream = Stream::new(reader, self.keep_reading);
+        stream.allow_zero_padding = self.allow_zero_padding;
+        stream.byte_limit = self.byte_limit;
+        stream.collapse_exception_returns = self.collapse_exception_returns;
+        stream.comparator_mask = self.comparator_mask;
+        stream.error_on_overflow = self.error_on_overflow;
+        stream.lenient_gts2 = self.lenient_gts2;
+        stream.pc_sample_decimation = self.pc_sample_decimation;
+        stream.skip_reserved_headers = self.skip_reserved_headers;
+        stream.sync_pattern = self.sync_pattern;
+        stream.unexpected_eof_error = self.unexpected_eof_error;
+        stream
+    }
+
     /// Returns the next packet in this stream
     ///
     /// The outer `Result` indicates I/O errors from reading from the inner `Reader` object.
@@ -89,60 +259,550 @@ where
     /// set to `false` (see constructor)
     ///
     /// `Ok(Some(..))` is the result of parsing the stream data into an ITM packet
+    ///
+    /// When `keep_reading` is `false` EOF is final, as is the case for in-memory inputs like
+    /// `&[u8]`, so an incomplete packet at the end of the data is reported as an error (see
+    /// `set_unexpected_eof_error`) before `Ok(None)` is returned.
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> io::Result<Option<Result<Packet, Error>>> {
         if self.at_eof {
             return Ok(None);
         }
 
-        'extract: loop {
-            match parse(&self.buffer[..self.len]) {
-                Ok(packet) => {
-                    self.rotate_left(usize::from(packet.len()));
+        loop {
+            if let Some(packet) = self.extract() {
+                return Ok(Some(packet));
+            }
 
-                    return Ok(Some(Ok(packet)));
+            // need more bytes
+            match self.fill() {
+                Ok(0) => {
+                    if self.keep_reading {
+                        self.check_idle();
+                    } else {
+                        return Ok(self.eof());
+                    }
                 }
-                // parsing error
-                Err(Either::Left(e)) => {
-                    // skip malformed packet
-                    self.rotate_left(usize::from(e.len()));
+                // got more data; try to extract a packet again
+                Ok(_) => {}
+                Err(e) => match e.kind() {
+                    ErrorKind::Interrupted => {}
+                    _ => return Err(e),
+                },
+            }
+        }
+    }
+
+    /// Like `next` but also returns the header byte of the decoded packet
+    ///
+    /// This is useful to debug the decoder or to compare its output against a reference decoder.
+    /// Decoding errors already include the offending header byte.
+    pub fn next_with_header(&mut self) -> io::Result<Option<Result<(u8, Packet), Error>>> {
+        Ok(self
+            .next()?
+            .map(|packet| packet.map(|packet| (self.last_header, packet))))
+    }
+
+    /// Like `next` but also returns the host time at which the packet was decoded
+    ///
+    /// This is unrelated to the ITM timestamps: it's meant to correlate the trace with host side
+    /// logs, e.g. when the target clock frequency is unknown.
+    pub fn next_with_recv_time(
+        &mut self,
+    ) -> io::Result<Option<Result<(Packet, SystemTime), Error>>> {
+        Ok(self
+            .next()?
+            .map(|packet| packet.map(|packet| (packet, SystemTime::now()))))
+    }
 
-                    return Ok(Some(Err(e)));
+    /// Returns the next Data trace data value packet produced by `comparator`
+    ///
+    /// If `access` is `Some` only packets of that access type are returned. All other packets,
+    /// and decoding errors, are skipped. `Ok(None)` means that EOF has been reached. See also
+    /// `set_comparator_mask`.
+    #[allow(clippy::unnecessary_map_or)]
+    pub fn next_data_value(
+        &mut self,
+        comparator: u8,
+        access: Option<Access>,
+    ) -> io::Result<Option<DataTraceDataValue>> {
+        while let Some(packet) = self.next()? {
+            if let Ok(Packet::DataTraceDataValue(dtdv)) = packet {
+                if dtdv.cmpn == comparator && access.map_or(true, |access| access == dtdv.access())
+                {
+                    return Ok(Some(dtdv));
                 }
-                Err(Either::Right(NeedMoreBytes)) => {
-                    // need more bytes
-                    'read: loop {
-                        match self.reader.read(&mut self.buffer[self.len..]) {
-                            Ok(0) => {
-                                if self.keep_reading {
-                                    continue 'read;
-                                } else {
-                                    // reached EOF
-                                    if self.len == 0 {
-                                        return Ok(None);
-                                    } else {
-                                        // truncated packet
-                                        self.at_eof = true;
-                                        return Ok(Some(Err(Error::MalformedPacket {
-                                            header: self.buffer[0],
-                                            len: self.len as u8,
-                                        })));
-                                    }
-                                }
-                            }
-                            Ok(len) => {
-                                self.len += len;
-                                // got more data; try to extract a packet again
-                                continue 'extract;
-                            }
-                            Err(e) => match e.kind() {
-                                ErrorKind::Interrupted => continue 'read,
-                                _ => return Err(e),
-                            },
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Decodes packets for at most `budget`, then returns the decoded packets (and errors)
+    ///
+    /// This alternates between `drain` and a single read from the underlying reader, and checks
+    /// the elapsed time before every read. A blocking reader can still make this overrun the
+    /// budget; pair it with a non-blocking or timed reader to decode incrementally from a UI or
+    /// real-time loop.
+    ///
+    /// Decoding stops early when the reader has no data: at EOF, when it returns `WouldBlock`
+    /// and, if `keep_reading` is set, when it returns `Ok(0)` (instead of retrying until the
+    /// budget runs out). The packets decoded so far are returned in all cases. Other I/O errors
+    /// are only returned if no packet was decoded; otherwise they are left for the next call.
+    pub fn pump_for(&mut self, budget: Duration) -> io::Result<Vec<Result<Packet, Error>>> {
+        let start = Instant::now();
+        let mut packets = vec![];
+
+        while !self.at_eof {
+            packets.extend(self.drain());
+
+            if start.elapsed() >= budget {
+                break;
+            }
+
+            match self.fill() {
+                Ok(0) => {
+                    if self.keep_reading {
+                        self.check_idle();
+                    } else {
+                        packets.extend(self.eof());
+                    }
+
+                    break;
+                }
+                Ok(_) => {}
+                Err(e) => match e.kind() {
+                    ErrorKind::Interrupted => {}
+                    ErrorKind::WouldBlock => break,
+                    _ if packets.is_empty() => return Err(e),
+                    _ => break,
+                },
+            }
+        }
+
+        Ok(packets)
+    }
+
+    /// Decodes the packets that are already buffered without reading from the underlying reader
+    ///
+    /// Decoding stops at the first incomplete packet, which stays buffered and will be completed
+    /// by a later call to `next`. This never blocks, which makes it suitable for polling loops.
+    pub fn drain(&mut self) -> Vec<Result<Packet, Error>> {
+        let mut packets = vec![];
+
+        while !self.at_eof {
+            match self.extract() {
+                Some(packet) => packets.push(packet),
+                None => break,
+            }
+        }
+
+        packets
+    }
+
+    /// Signals that no more data is coming and checks that the stream ends at a packet boundary
+    ///
+    /// This gives deterministic end of stream handling when the reader can't report EOF (e.g. a
+    /// non-blocking port) or when `keep_reading` is set. Call it once `drain` returns no more
+    /// packets. Returns `Error::MalformedPacket` (or `Error::UnexpectedEof`, see
+    /// `set_unexpected_eof_error`) if the buffered bytes form an incomplete packet. Afterwards the
+    /// buffer is empty and `next` returns `Ok(None)`.
+    pub fn finish(&mut self) -> Result<(), Error> {
+        self.at_eof = true;
+
+        if self.len == 0 {
+            Ok(())
+        } else {
+            let error = self.truncated();
+            self.record_error(self.len);
+            self.rotate_left(self.len);
+
+            Err(error)
+        }
+    }
+
+    /// Decodes the stream until EOF, sending every packet (or decoding error) through `tx`
+    ///
+    /// This is meant to be run on a producer thread. It returns `Ok(())` when EOF is reached or
+    /// when the receiving end of the channel has been dropped, and returns early on I/O errors.
+    pub fn run_into(&mut self, tx: Sender<Result<Packet, Error>>) -> io::Result<()> {
+        while let Some(packet) = self.next()? {
+            if tx.send(packet).is_err() {
+                // receiver hung up
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Decodes the stream until EOF and writes the re-encoded bytes of the packets that pass
+    /// `keep` to `w`
+    ///
+    /// Malformed packets are dropped. The output is itself a valid ITM stream, which makes this
+    /// suitable for filtering proxies (e.g. to drop the traffic of some stimulus ports).
+    ///
+    /// Instrumentation packets keep their stimulus port page: a `StimulusPortPage` packet is
+    /// written before them whenever the page of the output stream differs from theirs, even if
+    /// `keep` dropped the original `StimulusPortPage` packets.
+    pub fn reencode_filtered<W, F>(&mut self, mut w: W, mut keep: F) -> io::Result<()>
+    where
+        W: Write,
+        F: FnMut(&Packet) -> bool,
+    {
+        // stimulus port page of the output stream
+        let mut page = 0;
+
+        while let Some(packet) = self.next()? {
+            if let Ok(packet) = packet {
+                if keep(&packet) {
+                    match packet {
+                        Packet::Instrumentation(i) if i.page != page => {
+                            page = i.page;
+                            let spp = Packet::StimulusPortPage(StimulusPortPage { page });
+                            w.write_all(&spp.encode())?;
                         }
+                        Packet::StimulusPortPage(spp) => page = spp.page,
+                        Packet::Synchronization(_) => page = 0,
+                        _ => {}
+                    }
+
+                    w.write_all(&packet.encode())?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Skips the leading bytes that don't belong to a complete packet
+    ///
+    /// When attaching to a live trace the first byte is usually in the middle of a packet, and a
+    /// Synchronization packet may not arrive soon. This method fills the internal buffer, tries
+    /// to decode from each of the first `probe_bytes` byte offsets and moves to the offset that
+    /// yields the longest run of valid packets (the smallest one in case of a tie). The skipped
+    /// bytes are accounted for like malformed packets: they count as discarded bytes (see
+    /// `set_resync_callback`), update `last_error_offset` and are reported to the `Observer` as a
+    /// single `Error::MalformedPacket`.
+    ///
+    /// Returns the number of skipped bytes. Only the data that the reader has available is
+    /// buffered: buffering stops at the first `Ok(0)`, even if `keep_reading` is set.
+    pub fn find_alignment(&mut self, probe_bytes: usize) -> io::Result<usize> {
+        // buffer as much of the available data as possible to tell the candidates apart
+        while self.len < self.buffer.len() && self.byte_limit != Some(self.bytes_read) {
+            match self.fill() {
+                Ok(0) => {
+                    if self.keep_reading {
+                        self.check_idle();
                     }
+
+                    break;
                 }
+                Ok(_) => {}
+                Err(e) => match e.kind() {
+                    ErrorKind::Interrupted => {}
+                    _ => return Err(e),
+                },
+            }
+        }
+
+        let mut best = (0, 0);
+        for offset in 0..probe_bytes.min(self.len) {
+            let packets = decode_all(
+                &self.buffer[offset..self.len],
+                self.sync_pattern,
+                self.lenient_gts2,
+                &mut self.page.clone(),
+            )
+            .0
+            .iter()
+            .take_while(|packet| packet.is_ok())
+            .count();
+
+            if packets > best.1 {
+                best = (offset, packets);
+            }
+        }
+
+        let offset = best.0;
+        if offset != 0 {
+            let skipped = Error::MalformedPacket {
+                header: self.buffer[0],
+                len: offset as u8,
+            };
+
+            self.record_error(offset);
+            self.rotate_left(offset);
+            self.discarded += offset;
+
+            if let Some(observer) = self.observer.as_mut() {
+                observer.on_malformed(&skipped);
             }
         }
+
+        Ok(offset)
+    }
+
+    /// Treats runs of `0x00` bytes that don't form a Synchronization packet as padding
+    ///
+    /// Some ITM implementations pad the stream with zeros between packets. By default such a run
+    /// is reported as an `Error::InvalidSync`; if `allow` is set to `true` the zeros
+    /// are silently skipped and decoding resumes at the next header byte.
+    pub fn set_allow_zero_padding(&mut self, allow: bool) {
+        self.allow_zero_padding = allow;
+    }
+
+    /// Silently skips header bytes that the specification reserves
+    ///
+    /// By default a reserved header byte (e.g. a source packet header with `SS = 0b00` that doesn't
+    /// encode any other packet type) is reported as an `Error::ReservedHeader`; the stream then
+    /// continues with the next byte. If `skip` is set to `true` the header byte is consumed without
+    /// being reported. The skipped bytes still count as discarded bytes (see
+    /// `set_resync_callback`).
+    pub fn set_skip_reserved_headers(&mut self, skip: bool) {
+        self.skip_reserved_headers = skip;
+    }
+
+    /// Only yields every `keep_every`-th Periodic PC sample packet
+    ///
+    /// All other packets are passed through unchanged. The PC samples that are dropped don't
+    /// count towards `packet_count` nor update `last_packet`. By default all PC samples are
+    /// yielded.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if `keep_every` is zero
+    pub fn set_pc_sample_decimation(&mut self, keep_every: u64) {
+        assert!(keep_every != 0, "`keep_every` must be non-zero");
+
+        self.pc_sample_decimation = keep_every;
+    }
+
+    /// Changes what's considered a valid Synchronization packet
+    ///
+    /// Defaults to the ARMv7-M pattern; see `SyncPattern`
+    pub fn set_sync_pattern(&mut self, pattern: SyncPattern) {
+        self.sync_pattern = pattern;
+    }
+
+    /// Stops the stream at the first Overflow packet
+    ///
+    /// If `error` is set to `true` decoding an Overflow packet returns `Error::Overflow` and all
+    /// subsequent calls to `next` return `Ok(None)`. Use this when dropped trace data would
+    /// invalidate the analysis. Defaults to `false`.
+    pub fn set_error_on_overflow(&mut self, error: bool) {
+        self.error_on_overflow = error;
+    }
+
+    /// Accepts GTS2 packets whose payload is neither 4 (48-bit) nor 6 (64-bit) bytes long
+    ///
+    /// A truncated capture or a lost byte may cut a GTS2 packet short. By default such a packet is
+    /// reported as an `Error::MalformedPacket`. If `lenient` is set to `true` it's decoded from
+    /// whatever bytes were present and flagged as approximate (see `GTS2::is_approximate`).
+    /// Defaults to `false`.
+    pub fn set_lenient_gts2(&mut self, lenient: bool) {
+        self.lenient_gts2 = lenient;
+    }
+
+    /// Only yields the data trace packets of the DWT comparators enabled in `mask`
+    ///
+    /// Bit `n` of `mask` enables comparator `n`. The packets of disabled comparators are still
+    /// consumed (and reported by `comparators_seen`) but they are not yielded, nor do they count
+    /// towards `packet_count`. By default all comparators are enabled.
+    pub fn set_comparator_mask(&mut self, mask: u8) {
+        self.comparator_mask = mask;
+    }
+
+    /// Reports `Function::Return` exception trace packets as `Function::Exit`
+    ///
+    /// The distinction matters for call graph reconstruction: a return means that the processor
+    /// went back to an exception that was already active (e.g. after tail-chaining) rather than
+    /// leaving the exception. Only opt in if the consumer treats both the same way. Defaults to
+    /// `false`; see `Function::collapse_return`.
+    pub fn set_collapse_exception_returns(&mut self, collapse: bool) {
+        self.collapse_exception_returns = collapse;
+    }
+
+    /// Reports a packet truncated by EOF as `Error::UnexpectedEof`
+    ///
+    /// By default an incomplete packet at the end of the stream is reported as an
+    /// `Error::MalformedPacket`. If `error` is set to `true` it's reported as an
+    /// `Error::UnexpectedEof` that contains the leftover bytes instead, so they can be logged or
+    /// stitched to more data. Defaults to `false`.
+    pub fn set_unexpected_eof_error(&mut self, error: bool) {
+        self.unexpected_eof_error = error;
+    }
+
+    /// Limits the total number of bytes that will be read from the underlying reader
+    ///
+    /// Once `max` bytes have been read, `next` returns an I/O error if the reader has more data;
+    /// at most one byte past the limit is read to find out. Input that ends exactly at the limit
+    /// is handled like any other EOF. Packets that are already buffered are still decoded. This
+    /// bounds the work done on untrusted input, even when `keep_reading` is set.
+    pub fn set_byte_limit(&mut self, max: u64) {
+        self.byte_limit = Some(max.max(self.bytes_read));
+    }
+
+    /// Registers a callback that's invoked every time the stream resynchronizes
+    ///
+    /// When a Synchronization packet is decoded after malformed packets have been skipped,
+    /// `on_resync` is called with the number of bytes that were discarded since the previous
+    /// Synchronization packet. This can be used to quantify the quality of a noisy link.
+    pub fn set_resync_callback<F>(&mut self, on_resync: F)
+    where
+        F: FnMut(usize) + Send + 'static,
+    {
+        self.on_resync = Some(Box::new(on_resync));
+    }
+
+    /// Registers a callback that's invoked when the reader stalls
+    ///
+    /// This only applies when `keep_reading` is set. If the reader produces no data for
+    /// `timeout`, `on_idle` is called; it's called again every `timeout` for as long as the
+    /// stall lasts. This can be used to report an idle link. The check runs every time the reader
+    /// returns `Ok(0)` so the reader must not block indefinitely (e.g. use a read timeout).
+    pub fn set_idle_callback<F>(&mut self, timeout: Duration, on_idle: F)
+    where
+        F: FnMut() + Send + 'static,
+    {
+        self.idle_timeout = timeout;
+        self.on_idle = Some(Box::new(on_idle));
+    }
+
+    /// Registers a callback that reports how many bytes have been read from the reader
+    ///
+    /// `on_progress` is called with the total number of bytes read every time at least
+    /// `interval` more bytes have been read, and once more when EOF is reached, so the last
+    /// value is the size of the input. This is meant to drive progress bars when decoding large
+    /// files.
+    ///
+    /// # Panics
+    ///
+    /// This method panics if `interval` is zero
+    pub fn set_progress_callback<F>(&mut self, interval: u64, on_progress: F)
+    where
+        F: FnMut(u64) + Send + 'static,
+    {
+        assert!(interval != 0, "`interval` must be non-zero");
+
+        self.progress = Some(Progress {
+            interval,
+            on_progress: Box::new(on_progress),
+            reported: self.bytes_read,
+        });
+    }
+
+    /// Registers an `Observer` that's notified of every decoding event
+    ///
+    /// This replaces any previously registered observer.
+    pub fn set_observer<O>(&mut self, observer: O)
+    where
+        O: Observer + Send + 'static,
+    {
+        self.observer = Some(Box::new(observer));
+    }
+
+    /// Returns the most recently decoded packet, if any
+    pub fn last_packet(&self) -> Option<&Packet> {
+        self.last_packet.as_ref()
+    }
+
+    /// Returns the number of packets successfully decoded so far
+    ///
+    /// Malformed packets are not counted. Comparing this value across polls is a cheap way to
+    /// check whether a live stream is still producing data.
+    pub fn packet_count(&self) -> u64 {
+        self.packet_count
+    }
+
+    /// Returns the number of bits that were discarded to realign to the last Synchronization
+    /// packet
+    ///
+    /// ITM data is byte aligned so this is always a multiple of 8. Returns 0 if no Synchronization
+    /// packet has been decoded yet or if none had to be discarded.
+    pub fn last_sync_bits(&self) -> usize {
+        8 * self.last_resync
+    }
+
+    /// Returns the offset, in bytes from the start of the stream, of the next byte to decode
+    ///
+    /// Bytes that have been read from the underlying reader but not decoded yet are not included.
+    pub fn position(&self) -> u64 {
+        self.bytes_read - self.len as u64
+    }
+
+    /// Returns the offset, in bytes from the start of the stream, at which the most recently
+    /// reported decoding error starts
+    ///
+    /// This is useful to point at the exact location of a malformed packet in a capture. Returns
+    /// `None` if no error has been reported yet.
+    pub fn last_error_offset(&self) -> Option<u64> {
+        self.last_error_offset
+    }
+
+    /// Returns the bytes of the most recently reported decoding error
+    ///
+    /// This accounts for every byte of the stream, which matters to passthrough tools that must
+    /// not lose data: concatenating the `Packet::encode` output of the decoded packets and the
+    /// bytes of the errors in between reconstructs the input. Bytes skipped by `find_alignment`
+    /// are available here right after the call. Bytes dropped without an error (see
+    /// `set_allow_zero_padding` and `set_skip_reserved_headers`) and packets filtered out (see
+    /// `set_pc_sample_decimation` and `set_comparator_mask`) are not accounted for.
+    ///
+    /// Returns an empty slice if no error has been reported yet.
+    pub fn last_error_bytes(&self) -> &[u8] {
+        &self.last_error_bytes
+    }
+
+    /// Returns the DWT comparators that have produced data trace packets so far
+    pub fn comparators_seen(&self) -> BTreeSet<u8> {
+        (0..8)
+            .filter(|i| self.comparators & (1 << i) != 0)
+            .collect()
+    }
+
+    /// Returns `true` if both streams are in equivalent decoding states
+    ///
+    /// This compares the buffered bytes and the state accumulated while decoding (synchronization,
+    /// stimulus port page, counters, last packet, etc.) but neither the options nor the readers.
+    /// Two streams with the same options fed the same bytes end up in equivalent states; this is
+    /// meant for differential testing.
+    pub fn state_eq<R2>(&self, other: &Stream<R2>) -> bool
+    where
+        R2: Read,
+    {
+        self.buffer[..self.len] == other.buffer[..other.len]
+            && self.at_eof == other.at_eof
+            && self.bytes_read == other.bytes_read
+            && self.comparators == other.comparators
+            && self.discarded == other.discarded
+            && self.last_header == other.last_header
+            && self.last_packet == other.last_packet
+            && self.last_resync == other.last_resync
+            && self.packet_count == other.packet_count
+            && self.page == other.page
+            && self.pc_samples == other.pc_samples
+    }
+
+    /// Returns the decoding state of this stream
+    ///
+    /// The checkpoint covers the state accumulated while decoding (stimulus port page, counters,
+    /// offsets, etc.) but neither the options nor the reader. Bytes that have been read but not
+    /// decoded yet are not part of it either: decoding resumes at `Checkpoint::position`, so a
+    /// partial packet at the end of the buffer is read again. `last_packet` and `last_error_bytes`
+    /// are not restored.
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            comparators: self.comparators,
+            discarded: self.discarded,
+            last_error_offset: self.last_error_offset,
+            last_header: self.last_header,
+            last_resync: self.last_resync,
+            packet_count: self.packet_count,
+            page: self.page,
+            pc_samples: self.pc_samples,
+            position: self.position(),
+        }
     }
 
     /// Gets a reference to the underlying reader.
@@ -155,6 +815,221 @@ where
         &mut self.reader
     }
 
+    /// Unwraps this `Stream`, returning the underlying reader.
+    ///
+    /// Note that any leftover data in the internal buffer is lost.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    // Reads once from the underlying reader into the buffer, honoring the byte limit; returns the
+    // number of bytes read (`0` means EOF, or no data yet if `keep_reading` is set)
+    #[allow(clippy::io_other_error)]
+    fn fill(&mut self) -> io::Result<usize> {
+        let mut end = self.buffer.len();
+        // the limit has been reached; read a single byte to tell EOF from more data
+        let mut probe = false;
+        if let Some(limit) = self.byte_limit {
+            let remaining = limit - self.bytes_read;
+
+            if remaining == 0 {
+                probe = true;
+                end = self.len + 1;
+            } else if remaining < (end - self.len) as u64 {
+                end = self.len + remaining as usize;
+            }
+        }
+
+        let len = self.reader.read(&mut self.buffer[self.len..end])?;
+        if len != 0 {
+            if probe {
+                return Err(io::Error::new(ErrorKind::Other, "byte limit exceeded"));
+            }
+
+            self.len += len;
+            self.bytes_read += len as u64;
+            self.idle_since = None;
+            self.report_progress(false);
+        }
+
+        Ok(len)
+    }
+
+    // Handles EOF of the underlying reader; returns an error if the buffered bytes form an
+    // incomplete packet
+    fn eof(&mut self) -> Option<Result<Packet, Error>> {
+        self.report_progress(true);
+
+        if self.len == 0 {
+            None
+        } else {
+            // truncated packet
+            self.at_eof = true;
+            self.record_error(self.len);
+            Some(Err(self.truncated()))
+        }
+    }
+
+    // Tries to extract a packet from the buffered bytes; returns `None` if more bytes are needed
+    #[allow(clippy::manual_is_multiple_of)]
+    fn extract(&mut self) -> Option<Result<Packet, Error>> {
+        loop {
+            match parse(
+                &self.buffer[..self.len],
+                self.sync_pattern,
+                self.lenient_gts2,
+            ) {
+                Ok(mut packet) => {
+                    let header = self.buffer[0];
+                    let offset = self.position();
+                    self.rotate_left(packet.encoded_len());
+
+                    if let Packet::Overflow = packet {
+                        if self.error_on_overflow {
+                            self.at_eof = true;
+                            self.last_error_bytes = packet.encode();
+                            self.last_error_offset = Some(offset);
+                            return Some(Err(Error::Overflow));
+                        }
+                    }
+
+                    if let Packet::Synchronization(_) = packet {
+                        if self.discarded != 0 {
+                            if let Some(on_resync) = self.on_resync.as_mut() {
+                                on_resync(self.discarded);
+                            }
+
+                            if let Some(observer) = self.observer.as_mut() {
+                                observer.on_resync(8 * self.discarded);
+                            }
+                        }
+
+                        if let Some(observer) = self.observer.as_mut() {
+                            observer.on_sync();
+                        }
+
+                        self.last_resync = self.discarded;
+                        self.discarded = 0;
+                    }
+
+                    track_page(&mut self.page, &mut packet);
+
+                    if let Packet::ExceptionTrace(ref mut et) = packet {
+                        if self.collapse_exception_returns {
+                            et.function = et.function.collapse_return();
+                        }
+                    }
+
+                    if let Packet::PeriodicPcSample(_) = packet {
+                        self.pc_samples += 1;
+
+                        if self.pc_samples % self.pc_sample_decimation != 0 {
+                            continue;
+                        }
+                    }
+
+                    if let Some(cmpn) = packet.comparator() {
+                        self.comparators |= 1 << cmpn;
+
+                        if self.comparator_mask & (1 << cmpn) == 0 {
+                            continue;
+                        }
+                    }
+
+                    self.last_packet = Some(packet);
+                    self.packet_count += 1;
+                    self.last_header = header;
+
+                    if let Some(observer) = self.observer.as_mut() {
+                        observer.on_packet(&packet);
+                    }
+
+                    return Some(Ok(packet));
+                }
+                // parsing error
+                Err(Either::Left(e)) => {
+                    let len = usize::from(e.len());
+
+                    if self.allow_zero_padding {
+                        if let Error::InvalidSync { .. } = e {
+                            // too few zeros to be a Synchronization packet; treat them as padding
+                            self.rotate_left(len);
+                            continue;
+                        }
+                    }
+
+                    self.discarded += len;
+
+                    if let Some(observer) = self.observer.as_mut() {
+                        observer.on_malformed(&e);
+                    }
+
+                    if self.skip_reserved_headers {
+                        if let Error::ReservedHeader { .. } = e {
+                            self.rotate_left(len);
+                            continue;
+                        }
+                    }
+
+                    // skip malformed packet
+                    self.record_error(len);
+                    self.rotate_left(len);
+
+                    return Some(Err(e));
+                }
+                Err(Either::Right(NeedMoreBytes)) => return None,
+            }
+        }
+    }
+
+    // Records the offset and the bytes of an error made of the first `len` buffered bytes
+    fn record_error(&mut self, len: usize) {
+        self.last_error_offset = Some(self.position());
+        self.last_error_bytes.clear();
+        self.last_error_bytes.extend_from_slice(&self.buffer[..len]);
+    }
+
+    // the error reported for the incomplete packet left in the buffer at EOF
+    fn truncated(&self) -> Error {
+        if self.unexpected_eof_error {
+            Error::UnexpectedEof {
+                bytes: self.buffer[..self.len].to_vec(),
+            }
+        } else {
+            Error::MalformedPacket {
+                header: self.buffer[0],
+                len: self.len as u8,
+            }
+        }
+    }
+
+    // invokes the progress callback if enough bytes have been read since the last report
+    fn report_progress(&mut self, eof: bool) {
+        let bytes_read = self.bytes_read;
+
+        if let Some(progress) = self.progress.as_mut() {
+            if bytes_read - progress.reported >= progress.interval
+                || (eof && bytes_read != progress.reported)
+            {
+                progress.reported = bytes_read;
+                (progress.on_progress)(bytes_read);
+            }
+        }
+    }
+
+    // invokes `on_idle` if the reader has been stalled for `idle_timeout`
+    fn check_idle(&mut self) {
+        if let Some(on_idle) = self.on_idle.as_mut() {
+            let now = Instant::now();
+            let since = *self.idle_since.get_or_insert(now);
+
+            if now.duration_since(since) >= self.idle_timeout {
+                on_idle();
+                self.idle_since = Some(now);
+            }
+        }
+    }
+
     // like `slice.rotate_left` but doesn't touch the unused parts of the buffer
     fn rotate_left(&mut self, shift: usize) {
         for i in 0..self.len - shift {
@@ -165,37 +1040,299 @@ where
     }
 }
 
+/// Decodes the packets in `bytes` without a `Read`er
+///
+/// Returns the decoded packets (and decoding errors) plus the number of bytes at the end of
+/// `bytes` that form an incomplete packet. Prepend those leftover bytes to the next chunk to
+/// stitch arbitrarily split chunks together. The default `Stream` options are used. Stimulus
+/// port pages are tracked like `Stream` does, starting from page 0 on every call.
+pub fn decode_chunk(bytes: &[u8]) -> (Vec<Result<Packet, Error>>, usize) {
+    decode_all(bytes, SyncPattern::default(), false, &mut 0)
+}
+
+// Decodes all the complete packets in `bytes`, tracking the stimulus port `page`; returns the
+// packets (and decoding errors) plus the number of leftover bytes
+fn decode_all(
+    bytes: &[u8],
+    sync: SyncPattern,
+    lenient_gts2: bool,
+    page: &mut u8,
+) -> (Vec<Result<Packet, Error>>, usize) {
+    let mut packets = vec![];
+    let mut cursor = 0;
+
+    loop {
+        match parse(&bytes[cursor..], sync, lenient_gts2) {
+            Ok(mut packet) => {
+                cursor += packet.encoded_len();
+                track_page(page, &mut packet);
+                packets.push(Ok(packet));
+            }
+            Err(Either::Left(e)) => {
+                cursor += usize::from(e.len());
+                packets.push(Err(e));
+            }
+            Err(Either::Right(NeedMoreBytes)) => break (packets, bytes.len() - cursor),
+        }
+    }
+}
+
+// Updates the current stimulus port `page` and tags instrumentation packets with it
+fn track_page(page: &mut u8, packet: &mut Packet) {
+    match *packet {
+        Packet::StimulusPortPage(spp) => *page = spp.page,
+        // the trace source restarts so we assume it's back at the first page
+        Packet::Synchronization(_) => *page = 0,
+        Packet::Instrumentation(ref mut i) => i.page = *page,
+        _ => {}
+    }
+}
+
+/// The result of `guess_bit_order`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BitOrderGuess {
+    /// The sample decodes best as is
+    Normal,
+    /// The sample decodes best after reversing the bits of every byte
+    Reversed,
+    /// Both bit orders decode equally well
+    Unknown,
+}
+
+/// Guesses whether the bits of each byte of `sample` have been mirrored
+///
+/// Some logic analyzer tools export the SWO bit stream with the wrong bit order, which produces
+/// systematically wrong decodes. This decodes `sample` as is and with the bits of every byte
+/// reversed, skipping malformed packets, and reports which one discards
+/// fewer bytes. A few hundred bytes of trace are usually enough to tell them apart.
+pub fn guess_bit_order(sample: &[u8]) -> BitOrderGuess {
+    fn discarded_bytes(input: &[u8]) -> usize {
+        decode_chunk(input)
+            .0
+            .iter()
+            .filter_map(|packet| packet.as_ref().err())
+            .map(|e| usize::from(e.len()))
+            .sum()
+    }
+
+    let reversed = sample
+        .iter()
+        .map(|&byte| reverse_bits(byte))
+        .collect::<Vec<_>>();
+
+    let normal = discarded_bytes(sample);
+    let reversed = discarded_bytes(&reversed);
+    if normal < reversed {
+        BitOrderGuess::Normal
+    } else if reversed < normal {
+        BitOrderGuess::Reversed
+    } else {
+        BitOrderGuess::Unknown
+    }
+}
+
+// `u8::reverse_bits` is newer than our MSRV
+fn reverse_bits(byte: u8) -> u8 {
+    (0..8).fold(0, |reversed, i| reversed | ((byte >> i) & 1) << (7 - i))
+}
+
+/// Hooks into the decoding process of a `Stream`
+///
+/// All methods have empty default implementations so implementers only need to override the
+/// events they care about. This is meant for statistics, metrics or logging integrations; see
+/// `Stream::set_observer`.
+pub trait Observer {
+    /// Called for every packet yielded by the stream
+    fn on_packet(&mut self, _packet: &Packet) {}
+
+    /// Called for every malformed packet that's discarded, including the ones that are not
+    /// reported because of `Stream::set_skip_reserved_headers`
+    fn on_malformed(&mut self, _error: &Error) {}
+
+    /// Called when a Synchronization packet follows discarded bytes with the number of discarded
+    /// bits; this is called before `on_sync`
+    fn on_resync(&mut self, _bits: usize) {}
+
+    /// Called for every Synchronization packet
+    fn on_sync(&mut self) {}
+}
+
+// State of the progress callback of a `Stream`
+struct Progress {
+    interval: u64,
+    on_progress: Box<dyn FnMut(u64) + Send>,
+    // value passed to the last `on_progress` call
+    reported: u64,
+}
+
+/// The shape of a Synchronization packet
+///
+/// A Synchronization packet is a run of `0x00` bytes followed by a terminator byte. The ARMv7-M
+/// specification mandates "at least forty-seven 0 bits followed by a single 1 bit", i.e. at least
+/// 5 zero bytes followed by `0b1000_0000`; that's the `Default` pattern. Other implementations
+/// (e.g. vendor specific trace sources) may use a different pattern.
+///
+/// Note that the pattern is counted in bytes, not bits: ITM data is byte aligned so the 47 zero
+/// bits are the 40 bits of the five zero bytes plus the 7 low bits of the terminator. The
+/// minimal packet is therefore 6 bytes long, the same as the byte-counted check of older
+/// decoders, and longer runs of zeros are accepted as well.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SyncPattern {
+    min_zeros: u8,
+    terminator: u8,
+}
+
+impl SyncPattern {
+    /// Creates a pattern of at least `min_zeros` zero bytes (including the header) followed by
+    /// `terminator`
+    ///
+    /// # Panics
+    ///
+    /// This constructor panics if `min_zeros` or `terminator` are zero
+    pub fn new(min_zeros: u8, terminator: u8) -> Self {
+        assert!(min_zeros != 0, "`min_zeros` must be non-zero");
+        assert!(terminator != 0, "`terminator` must be non-zero");
+
+        SyncPattern {
+            min_zeros,
+            terminator,
+        }
+    }
+
+    /// Minimum number of zero bytes, including the header
+    pub fn min_zeros(&self) -> u8 {
+        self.min_zeros
+    }
+
+    /// The byte that terminates the packet
+    pub fn terminator(&self) -> u8 {
+        self.terminator
+    }
+}
+
+impl Default for SyncPattern {
+    fn default() -> Self {
+        SyncPattern::new(5, 0b1000_0000)
+    }
+}
+
+/// The decoding state of a `Stream`, see `Stream::checkpoint`
+///
+/// `Serialize` and `Deserialize` are implemented when the `serde` feature is enabled, so the state
+/// can be saved to disk and decoding resumed in a later run.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+pub struct Checkpoint {
+    comparators: u8,
+    discarded: usize,
+    last_error_offset: Option<u64>,
+    last_header: u8,
+    last_resync: usize,
+    packet_count: u64,
+    page: u8,
+    pc_samples: u64,
+    position: u64,
+}
+
+impl Checkpoint {
+    /// The offset, in bytes from the start of the stream, at which decoding resumes
+    ///
+    /// The reader passed to `Stream::from_checkpoint` must start at this offset.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+}
+
 /// ITM packet decoding errors
-#[derive(Debug, Error)]
+///
+/// `std::error::Error` is implemented when the `std` feature (enabled by default) is.
+#[derive(Debug)]
 pub enum Error {
     /// The packet starts with a reserved header byte
-    #[error("reserved header byte: {byte}")]
     ReservedHeader {
         /// The header byte
         byte: u8,
     },
 
     /// The packet doesn't adhere to the (ARMv7-M) specification
-    #[error("malformed packet of length {len} with header {header}")]
     MalformedPacket {
         /// The header of the malformed packet
         header: u8,
         /// Length of the malformed packet in bytes, including the header
         len: u8,
     },
+
+    /// A run of zeros that doesn't form a valid Synchronization packet
+    ///
+    /// Either the run is too short or it's not followed by the terminator byte (see
+    /// `SyncPattern`)
+    InvalidSync {
+        /// Number of zero bytes observed, including the header
+        observed: u8,
+        /// Minimum number of zero bytes of a valid Synchronization packet
+        required: u8,
+    },
+
+    /// An Overflow packet was decoded (see `Stream::set_error_on_overflow`)
+    Overflow,
+
+    /// The stream ended in the middle of a packet (see `Stream::set_unexpected_eof_error`)
+    UnexpectedEof {
+        /// The bytes of the incomplete packet
+        bytes: Vec<u8>,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::ReservedHeader { byte } => write!(f, "reserved header byte: {}", byte),
+            Error::MalformedPacket { header, len } => write!(
+                f,
+                "malformed packet of length {} with header {}",
+                len, header
+            ),
+            Error::InvalidSync { observed, required } => write!(
+                f,
+                "invalid synchronization packet: {} zero bytes, at least {} required",
+                observed, required
+            ),
+            Error::Overflow => f.write_str("trace data was lost due to an overflow"),
+            Error::UnexpectedEof { ref bytes } => write!(
+                f,
+                "unexpected EOF after {} byte(s) of a packet",
+                bytes.len()
+            ),
+        }
+    }
 }
 
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
 impl Error {
     fn len(&self) -> u8 {
         match *self {
             Error::ReservedHeader { .. } => 1,
             Error::MalformedPacket { len, .. } => len,
+            Error::InvalidSync { observed, .. } => observed,
+            Error::Overflow => 1,
+            Error::UnexpectedEof { ref bytes } => bytes.len() as u8,
         }
     }
 }
 
 /// An ITM packet
-#[derive(Clone, Copy, Debug)]
+///
+/// Decoding is strict: the reserved bits and the length constraints of Appendix D4 of the ARMv7-M
+/// specification are checked and packets that violate them are reported as
+/// `Error::MalformedPacket`, so there's no separate strict mode. The only relaxed format is opt-in
+/// (see `Stream::set_lenient_gts2`).
+///
+/// Extension packets are only decoded in their single byte form. The specification allows them to
+/// continue (C bit set) into up to four payload bytes, but their layout is implementation defined,
+/// so such headers (`0b1xxx_1x00`) are reported as `Error::ReservedHeader`.
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Packet {
     /// Overflow packet
     Overflow,
@@ -209,8 +1346,10 @@ pub enum Packet {
     GTS1(GTS1),
     /// Global timestamp packet (format 2)
     GTS2(GTS2),
-    /// Stimulus Port Page (Extension packet)
+    /// Stimulus Port Page (single byte Extension packet)
     StimulusPortPage(StimulusPortPage),
+    /// Extension packet for hardware source information (single byte form)
+    Extension(Extension),
     /// Event Counter
     EventCounter(EventCounter),
     /// Exception Trace
@@ -227,21 +1366,18 @@ pub enum Packet {
 
 impl Packet {
     /// The length of this packet in bytes, including the header
-    fn len(&self) -> u8 {
-        match *self {
+    ///
+    /// This is the number of bytes the packet occupies in the ITM stream.
+    pub fn encoded_len(&self) -> usize {
+        usize::from(match *self {
             Packet::Overflow => 1,
             Packet::Synchronization(s) => s.len(),
             Packet::Instrumentation(i) => 1 /* header */ + i.size,
             Packet::LocalTimestamp(lt) => lt.len,
             Packet::GTS1(gt) => gt.len,
-            Packet::GTS2(gt) => {
-                if gt.b64 {
-                    7
-                } else {
-                    5
-                }
-            }
+            Packet::GTS2(gt) => gt.len,
             Packet::StimulusPortPage(_) => 1,
+            Packet::Extension(_) => 1,
             Packet::EventCounter(_) => 2,
             Packet::ExceptionTrace(_) => 3,
             Packet::PeriodicPcSample(pps) => {
@@ -254,12 +1390,194 @@ impl Packet {
             Packet::DataTracePcValue(_) => 5,
             Packet::DataTraceAddress(_) => 3,
             Packet::DataTraceDataValue(dtdv) => 1 /* header */ + dtdv.size,
+        })
+    }
+
+    /// The stimulus port of an Instrumentation packet, `None` for other packets
+    ///
+    /// This is the port relative to the current page; see `Instrumentation::paged_port`.
+    pub fn port(&self) -> Option<u8> {
+        match *self {
+            Packet::Instrumentation(i) => Some(i.port),
+            _ => None,
+        }
+    }
+
+    /// The DWT comparator of a data trace packet, `None` for other packets
+    pub fn comparator(&self) -> Option<u8> {
+        match *self {
+            Packet::DataTracePcValue(DataTracePcValue { cmpn, .. })
+            | Packet::DataTraceAddress(DataTraceAddress { cmpn, .. })
+            | Packet::DataTraceDataValue(DataTraceDataValue { cmpn, .. }) => Some(cmpn),
+            _ => None,
+        }
+    }
+
+    /// A `hexdump -C` style dump of the bytes this packet encodes to (see `encode`)
+    ///
+    /// Each line holds an offset, up to 16 bytes in hex and their ASCII representation; the last
+    /// line is the total number of bytes.
+    pub fn hexdump(&self) -> String {
+        use core::fmt::Write;
+
+        let bytes = self.encode();
+        let mut dump = String::new();
+
+        for (i, line) in bytes.chunks(16).enumerate() {
+            write!(dump, "{:08x}  ", 16 * i).ok();
+
+            for j in 0..16 {
+                match line.get(j) {
+                    Some(byte) => write!(dump, "{:02x} ", byte).ok(),
+                    None => dump.write_str("   ").ok(),
+                };
+
+                if j == 7 {
+                    dump.push(' ');
+                }
+            }
+
+            dump.push_str(" |");
+            dump.extend(line.iter().map(|&byte| {
+                if byte.is_ascii_graphic() || byte == b' ' {
+                    char::from(byte)
+                } else {
+                    '.'
+                }
+            }));
+            dump.push_str("|\n");
         }
+
+        writeln!(dump, "{:08x}", bytes.len()).ok();
+
+        dump
+    }
+
+    /// Encodes this packet back into ITM bytes
+    ///
+    /// The output is `encoded_len` bytes long and decodes back into the same packet, except for
+    /// the stimulus port page of `Instrumentation` packets: it isn't part of the packet encoding
+    /// and must be conveyed by a preceding `StimulusPortPage` packet (`Stream::reencode_filtered`
+    /// takes care of this). Synchronization packets are always terminated with the default
+    /// `0b1000_0000` byte (see `SyncPattern`).
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.encoded_len());
+
+        match *self {
+            Packet::Overflow => bytes.push(0b0111_0000),
+            Packet::Synchronization(s) => {
+                bytes.resize(usize::from(s.len) - 1, 0);
+                bytes.push(0b1000_0000);
+            }
+            Packet::Instrumentation(i) => {
+                bytes.push((i.port << 3) | encode_size(i.size));
+                bytes.extend_from_slice(i.payload());
+            }
+            Packet::LocalTimestamp(lt) => {
+                if lt.len == 1 {
+                    // format 2
+                    bytes.push((lt.delta as u8) << 4);
+                } else {
+                    // format 1
+                    bytes.push(0b1100_0000 | (lt.tc << 4));
+                    push_continued(&mut bytes, u64::from(lt.delta), lt.len - 1);
+                }
+            }
+            Packet::GTS1(gt) => {
+                bytes.push(0b1001_0100);
+                push_continued(&mut bytes, u64::from(gt.bits), gt.len - 1);
+
+                if gt.len == 5 {
+                    // the fourth payload byte has extra info
+                    let last = bytes.len() - 1;
+                    bytes[last] |= (u8::from(gt.clk_ch) << 5) | (u8::from(gt.wrap) << 6);
+                }
+            }
+            Packet::GTS2(gt) => {
+                bytes.push(0b1011_0100);
+                push_continued(&mut bytes, gt.bits, gt.len - 1);
+            }
+            Packet::StimulusPortPage(spp) => bytes.push((spp.page << 4) | 0b0000_1000),
+            Packet::Extension(e) => bytes.push((e.info << 4) | 0b0000_1100),
+            Packet::EventCounter(ec) => bytes.extend_from_slice(&[0b0000_0101, ec.payload]),
+            Packet::ExceptionTrace(et) => {
+                let function = match et.function {
+                    Function::Enter => 0b000_1000,
+                    Function::Exit => 0b001_0000,
+                    Function::Return => 0b001_1000,
+                };
+
+                bytes.extend_from_slice(&[
+                    0b0000_1110,
+                    et.number as u8,
+                    (function << 1) | (et.number >> 8) as u8 & 1,
+                ]);
+            }
+            Packet::PeriodicPcSample(pps) => match pps.pc {
+                Some(pc) => {
+                    bytes.push(0b0001_0111);
+                    push_le(&mut bytes, u64::from(pc), 4);
+                }
+                None => bytes.extend_from_slice(&[0b0001_0101, 0]),
+            },
+            Packet::DataTracePcValue(dtpv) => {
+                bytes.push(0b0100_0111 | (dtpv.cmpn << 4));
+                push_le(&mut bytes, u64::from(dtpv.pc), 4);
+            }
+            Packet::DataTraceAddress(dta) => {
+                bytes.push(0b0100_1110 | (dta.cmpn << 4));
+                push_le(&mut bytes, u64::from(dta.address), 2);
+            }
+            Packet::DataTraceDataValue(dtdv) => {
+                bytes.push(
+                    0b1000_0100
+                        | (dtdv.cmpn << 4)
+                        | (u8::from(dtdv.wnr) << 3)
+                        | encode_size(dtdv.size),
+                );
+                bytes.extend_from_slice(dtdv.value());
+            }
+        }
+
+        bytes
+    }
+}
+
+// inverse of the SS field decoding
+fn encode_size(size: u8) -> u8 {
+    match size {
+        1 => 0b01,
+        2 => 0b10,
+        _ => 0b11,
+    }
+}
+
+// pushes the `len` least significant bytes of `value`, in little endian order
+fn push_le(bytes: &mut Vec<u8>, value: u64, len: u8) {
+    for i in 0..len {
+        bytes.push((value >> (8 * i)) as u8);
+    }
+}
+
+// pushes `value` as `len` bytes of 7 bits each; all but the last have the C (Continue) bit set
+fn push_continued(bytes: &mut Vec<u8>, value: u64, len: u8) {
+    for i in 0..len {
+        let mut byte = (value >> (7 * i)) as u8 & 0b0111_1111;
+
+        if i != len - 1 {
+            byte |= 0b1000_0000;
+        }
+
+        bytes.push(byte);
     }
 }
 
 /// Tries to parse an ITM packet from the start of the given buffer
-fn parse(input: &[u8]) -> Result<Packet, Either<Error, NeedMoreBytes>> {
+fn parse(
+    input: &[u8],
+    sync: SyncPattern,
+    lenient_gts2: bool,
+) -> Result<Packet, Either<Error, NeedMoreBytes>> {
     let header = input.first().cloned().ok_or(Either::Right(NeedMoreBytes))?;
 
     match Header::parse(header).map_err(Either::Left)? {
@@ -268,22 +1586,23 @@ fn parse(input: &[u8]) -> Result<Packet, Either<Error, NeedMoreBytes>> {
 
             loop {
                 match input.get(usize::from(cursor)) {
-                    Some(&0b0000_0000) => {
+                    Some(&0b0000_0000) if cursor < 255 => {
                         // still within the synchronization packet
                         cursor += 1;
                         continue;
                     }
-                    Some(&0b1000_0000) if cursor >= 5 => {
+                    Some(&byte) if byte == sync.terminator && cursor >= sync.min_zeros => {
                         //  "Synchronization packet is at least forty-seven 0 bits followed by single 1
-                        //  bit"
+                        //  bit" (default pattern)
                         // valid synchronization packet
                         break Ok(Packet::Synchronization(Synchronization { len: cursor + 1 }));
                     }
                     Some(_) => {
-                        // malformed packet
-                        break Err(Either::Left(Error::MalformedPacket {
-                            header,
-                            len: cursor,
+                        // malformed packet; this includes zero runs too long to count, which
+                        // are reported in chunks of 255 bytes
+                        break Err(Either::Left(Error::InvalidSync {
+                            observed: cursor,
+                            required: sync.min_zeros,
                         }));
                     }
                     None => {
@@ -306,8 +1625,9 @@ fn parse(input: &[u8]) -> Result<Packet, Either<Error, NeedMoreBytes>> {
 
                 Ok(Packet::Instrumentation(Instrumentation {
                     buffer,
-                    size,
+                    page: 0,
                     port,
+                    size,
                 }))
             } else {
                 // need more bytes
@@ -423,6 +1743,7 @@ fn parse(input: &[u8]) -> Result<Packet, Either<Error, NeedMoreBytes>> {
 
         Header::GTS2 => {
             // parse the payload -- this is similar to parsing LTS1 payload
+            let mut approximate = false;
             let mut bits = 0;
             let mut cursor = 1u8;
             let b64 = loop {
@@ -453,25 +1774,43 @@ fn parse(input: &[u8]) -> Result<Packet, Either<Error, NeedMoreBytes>> {
                         } else {
                             break true;
                         }
+                    } else if lenient_gts2 && cursor < 6 {
+                        // truncated packet; a 5-byte payload can only be a cut short 64-bit one
+                        approximate = true;
+                        break cursor > 4;
                     } else {
                         return Err(Either::Left(Error::MalformedPacket {
                             header,
                             len: cursor,
                         }));
                     }
+                } else if cursor == 6 {
+                    // payloads are at most 6 bytes in size; like in GTS1 the malformed packet is
+                    // considered to end before this byte, which could be a new header
+                    return Err(Either::Left(Error::MalformedPacket {
+                        header,
+                        len: cursor,
+                    }));
                 } else {
                     // Continue (C) bit is one
                     cursor += 1;
                 }
             };
 
-            Ok(Packet::GTS2(GTS2 { bits, b64 }))
+            Ok(Packet::GTS2(GTS2 {
+                approximate,
+                bits,
+                b64,
+                len: cursor + 1,
+            }))
         }
 
         Header::StimulusPortPage { page } => {
             Ok(Packet::StimulusPortPage(StimulusPortPage { page }))
         }
 
+        Header::Extension { info } => Ok(Packet::Extension(Extension { info })),
+
         Header::EventCounter => {
             let payload = input.get(1).cloned().ok_or(Either::Right(NeedMoreBytes))?;
 
@@ -601,6 +1940,10 @@ enum Header {
     StimulusPortPage {
         page: u8,
     },
+    /// D4.2.6 Extension packet with the SH bit set (hardware source)
+    Extension {
+        info: u8,
+    },
 
     // D4.3 Hardware Source Packets
     /// D4.3.1 Event counter packet
@@ -659,15 +2002,21 @@ impl Header {
                     // 0b11TC_0000
                     let tc = (byte >> 4) & 0b11;
                     Header::LTS1 { tc }
-                } else if byte & 0b1000_1111 == 0b0000_1000 {
-                    // 0b0xxx_1000
-                    let page = (byte >> 4) & 0b111;
-
-                    Header::StimulusPortPage { page }
+                } else if byte & 0b1000_1011 == 0b0000_1000 {
+                    // 0b0xxx_1S00; Extension packets with the C bit set (0b1xxx_1S00) carry an
+                    // implementation defined payload and fall through to the reserved header case
+                    let ex = (byte >> 4) & 0b111;
+
+                    if byte & 0b0100 == 0 {
+                        // SH = 0: ITM source; the payload is the stimulus port page
+                        Header::StimulusPortPage { page: ex }
+                    } else {
+                        Header::Extension { info: ex }
+                    }
                 } else {
                     // 0bAAAA_A0SS
                     match byte & 0b111 {
-                        0b001 | 0b010 | 0b011 => {
+                        0b001..=0b011 => {
                             let port = byte >> 3;
                             let size = match byte & 0b11 {
                                 0b01 => 1,
@@ -689,7 +2038,7 @@ impl Header {
                             } else if byte & 0b1100_0100 == 0b1000_0100 {
                                 // 0b01xx_W1SS
                                 match byte & 0b11 {
-                                    0b01 | 0b10 | 0b11 => {
+                                    0b01..=0b11 => {
                                         let size = match byte & 0b11 {
                                             0b01 => 1,
                                             0b10 => 2,
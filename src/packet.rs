@@ -3,32 +3,44 @@
 use core::fmt;
 
 /// Synchronization packet
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Synchronization {
     pub(crate) len: u8,
 }
 
 impl Synchronization {
     /// The length in bytes of this synchronization packet
+    #[allow(clippy::len_without_is_empty)]
     pub fn len(&self) -> u8 {
         self.len
     }
 }
 
 /// Instrumentation packet
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq)]
 pub struct Instrumentation {
     pub(crate) buffer: [u8; 4],
+    // stimulus port page in effect when this packet was decoded
+    pub(crate) page: u8,
     pub(crate) port: u8,
     pub(crate) size: u8,
 }
 
 impl Instrumentation {
-    /// The stimulus port that generated this packet
+    /// The stimulus port that generated this packet, relative to the current page
     pub fn port(&self) -> u8 {
         self.port
     }
 
+    /// The stimulus port that generated this packet, taking stimulus port pages into account
+    ///
+    /// This is `32 * page + port`, in the range `0..=255`. The page is set by the most recent
+    /// `StimulusPortPage` packet decoded by the `Stream` and goes back to 0 on Synchronization
+    /// packets. Packets not decoded by a `Stream` are on page 0.
+    pub fn paged_port(&self) -> u16 {
+        32 * u16::from(self.page) + u16::from(self.port)
+    }
+
     /// The payload of this packet
     pub fn payload(&self) -> &[u8] {
         &self.buffer[..usize::from(self.size)]
@@ -38,6 +50,7 @@ impl Instrumentation {
 impl fmt::Debug for Instrumentation {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("Instrumentation")
+            .field("page", &self.page)
             .field("payload", &&self.buffer[..usize::from(self.size)])
             .field("port", &self.port)
             .finish()
@@ -45,7 +58,7 @@ impl fmt::Debug for Instrumentation {
 }
 
 /// Local timestamp packet
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct LocalTimestamp {
     pub(crate) delta: u32,
     // TC[1:0] bits
@@ -70,6 +83,13 @@ impl LocalTimestamp {
         self.tc == 0
     }
 
+    /// Either the timestamp or the output of the associated ITM or DWT packet is delayed
+    ///
+    /// This is an alias of `!is_precise()`, for code that checks timestamp quality.
+    pub fn is_delayed(&self) -> bool {
+        !self.is_precise()
+    }
+
     /// The local timestamp value is delayed relative to the ITM or DWT data.
     ///
     /// The value in the TS field is the timestamp counter value when the Local timestamp packet is
@@ -92,7 +112,7 @@ impl LocalTimestamp {
 }
 
 /// Global timestamp packet (format 1)
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct GTS1 {
     pub(crate) bits: u32,
     pub(crate) clk_ch: bool,
@@ -116,7 +136,7 @@ impl GTS1 {
         self.clk_ch
     }
 
-    /// The value of global timestamp bits TS[47:26] or TS[63:26] have changed since the last GTS2
+    /// The value of global timestamp bits `TS[47:26]` or `TS[63:26]` have changed since the last GTS2
     /// packet output by the ITM
     pub fn has_wrapped(&self) -> bool {
         self.wrap
@@ -124,10 +144,14 @@ impl GTS1 {
 }
 
 /// Global timestamp packet (format 2)
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct GTS2 {
+    // the payload was cut short (lenient mode)
+    pub(crate) approximate: bool,
     pub(crate) bits: u64,
     pub(crate) b64: bool,
+    // Size of this packet in bytes, including the header
+    pub(crate) len: u8,
 }
 
 impl GTS2 {
@@ -140,10 +164,17 @@ impl GTS2 {
     pub fn is_64_bit(&self) -> bool {
         self.b64
     }
+
+    /// The packet was cut short so `bits` only contains the low-order bits that were present
+    ///
+    /// Only decoded by streams in lenient mode; see `Stream::set_lenient_gts2`.
+    pub fn is_approximate(&self) -> bool {
+        self.approximate
+    }
 }
 
 /// Stimulus Port Page (Extension packet)
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct StimulusPortPage {
     pub(crate) page: u8,
 }
@@ -155,8 +186,28 @@ impl StimulusPortPage {
     }
 }
 
+/// Extension packet with the SH bit set
+///
+/// The SH bit of an Extension packet selects the meaning of its payload. When it's cleared the
+/// packet is a `StimulusPortPage`; when it's set the payload carries information about the
+/// hardware source, which the ARMv7-M specification leaves implementation defined.
+///
+/// Only the single byte form is decoded. An Extension header with the C bit set is reported as
+/// `Error::ReservedHeader` because the layout of its payload bytes is implementation defined too.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Extension {
+    pub(crate) info: u8,
+}
+
+impl Extension {
+    /// The raw extension information (3-bit value)
+    pub fn info(&self) -> u8 {
+        self.info
+    }
+}
+
 /// Event counter packet
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct EventCounter {
     pub(crate) payload: u8,
 }
@@ -188,9 +239,62 @@ impl EventCounter {
     }
 
     /// has POSTCNT wrapped around?
+    ///
+    /// POSTCNT is the post-scaler of the cycle counter (CYCCNT): it counts down on every tap of
+    /// CYCCNT, so its wrap events are a scaled down measure of elapsed cycles.
     pub fn post(&self) -> bool {
         self.payload & (1 << 5) != 0
     }
+
+    /// The counters that have wrapped around, one item per counter
+    pub fn wrapped(&self) -> impl Iterator<Item = Counter> {
+        let payload = self.payload;
+
+        COUNTERS
+            .iter()
+            .cloned()
+            .filter(move |&counter| payload & (1 << counter as u8) != 0)
+    }
+}
+
+static COUNTERS: [Counter; 6] = [
+    Counter::Cpi,
+    Counter::Exc,
+    Counter::Sleep,
+    Counter::Lsu,
+    Counter::Fold,
+    Counter::Post,
+];
+
+/// A DWT profiling counter reported by the event counter packet
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Counter {
+    /// CPICNT
+    Cpi = 0,
+    /// EXCCNT
+    Exc = 1,
+    /// SLEEPCNT
+    Sleep = 2,
+    /// LSUCNT
+    Lsu = 3,
+    /// FOLDCNT
+    Fold = 4,
+    /// POSTCNT
+    Post = 5,
+}
+
+impl Counter {
+    /// The name of the DWT register that holds this counter, e.g. "CPICNT"
+    pub fn register_name(&self) -> &'static str {
+        match *self {
+            Counter::Cpi => "CPICNT",
+            Counter::Exc => "EXCCNT",
+            Counter::Sleep => "SLEEPCNT",
+            Counter::Lsu => "LSUCNT",
+            Counter::Fold => "FOLDCNT",
+            Counter::Post => "POSTCNT",
+        }
+    }
 }
 
 /// The action taken by the processor
@@ -204,8 +308,21 @@ pub enum Function {
     Return,
 }
 
+impl Function {
+    /// Maps `Return` to `Exit`, leaving the other actions unchanged
+    ///
+    /// Use this when the difference between exiting an exception and returning to one (e.g.
+    /// after tail-chaining) doesn't matter. Note that this loses information.
+    pub fn collapse_return(self) -> Self {
+        match self {
+            Function::Return => Function::Exit,
+            function => function,
+        }
+    }
+}
+
 /// Exception trace packet
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct ExceptionTrace {
     pub(crate) function: Function,
     pub(crate) number: u16,
@@ -224,7 +341,7 @@ impl ExceptionTrace {
 }
 
 /// Periodic PC sample packet
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct PeriodicPcSample {
     pub(crate) pc: Option<u32>,
 }
@@ -239,7 +356,7 @@ impl PeriodicPcSample {
 }
 
 /// Data trace PC packet
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct DataTracePcValue {
     pub(crate) cmpn: u8,
     pub(crate) pc: u32,
@@ -258,7 +375,7 @@ impl DataTracePcValue {
 }
 
 /// Data trace address packet
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct DataTraceAddress {
     pub(crate) cmpn: u8,
     pub(crate) address: u16,
@@ -276,8 +393,17 @@ impl DataTraceAddress {
     }
 }
 
+/// A memory access type
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    /// Read access
+    Read,
+    /// Write access
+    Write,
+}
+
 /// Data trace data value packet
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq)]
 pub struct DataTraceDataValue {
     pub(crate) buffer: [u8; 4],
     pub(crate) cmpn: u8,
@@ -315,4 +441,32 @@ impl DataTraceDataValue {
     pub fn write_access(&self) -> bool {
         self.wnr
     }
+
+    /// The type of access
+    pub fn access(&self) -> Access {
+        if self.wnr {
+            Access::Write
+        } else {
+            Access::Read
+        }
+    }
+
+    /// One line summary of this packet, e.g. "comparator 3 wrote 0x1234"
+    ///
+    /// The value is interpreted as a little endian integer of `value().len()` bytes.
+    pub fn describe(&self) -> String {
+        let value = self
+            .value()
+            .iter()
+            .rev()
+            .fold(0u32, |acc, &byte| (acc << 8) | u32::from(byte));
+
+        format!(
+            "comparator {} {} {:#0width$x}",
+            self.cmpn,
+            if self.wnr { "wrote" } else { "read" },
+            value,
+            width = 2 + 2 * usize::from(self.size),
+        )
+    }
 }
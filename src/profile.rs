@@ -0,0 +1,317 @@
+//! Statistical profiling and trace statistics
+
+use std::collections::HashMap;
+
+use crate::{packet::Function, Error, Packet};
+
+/// Statistical profiler fed with Periodic PC sample packets
+///
+/// Each sampled PC is bucketed by address; samples taken while the core was sleeping are counted
+/// separately. Packets other than Periodic PC samples are ignored.
+#[derive(Clone, Debug, Default)]
+pub struct PcProfiler {
+    hits: HashMap<u32, u64>,
+    sleeping: u64,
+}
+
+impl PcProfiler {
+    /// Creates an empty profile
+    pub fn new() -> Self {
+        PcProfiler::default()
+    }
+
+    /// Adds `packet` to the profile, if it's a Periodic PC sample
+    pub fn observe(&mut self, packet: &Packet) {
+        if let Packet::PeriodicPcSample(pps) = *packet {
+            match pps.pc() {
+                Some(pc) => *self.hits.entry(pc).or_insert(0) += 1,
+                None => self.sleeping += 1,
+            }
+        }
+    }
+
+    /// Number of samples taken at each PC value
+    pub fn hits(&self) -> &HashMap<u32, u64> {
+        &self.hits
+    }
+
+    /// Number of samples taken while the core was sleeping
+    pub fn sleeping(&self) -> u64 {
+        self.sleeping
+    }
+
+    /// Total number of samples, including the sleeping ones
+    pub fn samples(&self) -> u64 {
+        self.hits.values().sum::<u64>() + self.sleeping
+    }
+
+    /// Returns the (at most) `n` most sampled PC values along with their hit counts
+    ///
+    /// The result is sorted by decreasing hit count; ties are broken by increasing address.
+    pub fn top(&self, n: usize) -> Vec<(u32, u64)> {
+        let mut hits = self
+            .hits
+            .iter()
+            .map(|(&pc, &count)| (pc, count))
+            .collect::<Vec<_>>();
+        hits.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        hits.truncate(n);
+        hits
+    }
+}
+
+/// Tracks the sleep / wake transitions of the core from Periodic PC sample packets
+///
+/// A run of consecutive samples taken while the core was sleeping is a sleep period; its duration
+/// is measured in number of samples. Packets other than Periodic PC samples are ignored.
+#[derive(Clone, Debug, Default)]
+pub struct SleepTracker {
+    // number of consecutive sleeping samples seen so far, if currently sleeping
+    asleep: Option<u64>,
+    sleeps: u64,
+}
+
+/// A sleep / wake transition
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SleepEvent {
+    /// The core went to sleep
+    Sleep,
+    /// The core woke up after sleeping for `samples` samples
+    Wake {
+        /// Duration of the sleep period, in samples
+        samples: u64,
+    },
+}
+
+impl SleepTracker {
+    /// Creates a tracker that assumes that the core is initially running
+    pub fn new() -> Self {
+        SleepTracker::default()
+    }
+
+    /// Feeds `packet` to the tracker and returns the transition it caused, if any
+    pub fn observe(&mut self, packet: &Packet) -> Option<SleepEvent> {
+        if let Packet::PeriodicPcSample(pps) = *packet {
+            match (pps.pc(), self.asleep.as_mut()) {
+                (None, Some(samples)) => *samples += 1,
+                (None, None) => {
+                    self.asleep = Some(1);
+                    self.sleeps += 1;
+
+                    return Some(SleepEvent::Sleep);
+                }
+                (Some(_), Some(&mut samples)) => {
+                    self.asleep = None;
+
+                    return Some(SleepEvent::Wake { samples });
+                }
+                (Some(_), None) => {}
+            }
+        }
+
+        None
+    }
+
+    /// Is the core currently sleeping?
+    pub fn is_sleeping(&self) -> bool {
+        self.asleep.is_some()
+    }
+
+    /// Number of times the core went to sleep
+    pub fn sleeps(&self) -> u64 {
+        self.sleeps
+    }
+}
+
+/// Histogram of the reserved header bytes found in a stream
+///
+/// `Stream` skips a reserved header byte and continues decoding, so feeding every error it reports
+/// into this tally gives an overview of the unrecognized headers in a trace, which is useful when
+/// reverse engineering the trace output of unknown firmware.
+#[derive(Clone, Debug, Default)]
+pub struct HeaderTally {
+    counts: HashMap<u8, u64>,
+}
+
+impl HeaderTally {
+    /// Creates an empty tally
+    pub fn new() -> Self {
+        HeaderTally::default()
+    }
+
+    /// Adds `error` to the tally, if it's a reserved header error
+    pub fn observe(&mut self, error: &Error) {
+        if let Error::ReservedHeader { byte } = *error {
+            *self.counts.entry(byte).or_insert(0) += 1;
+        }
+    }
+
+    /// Number of occurrences of each reserved header byte
+    pub fn counts(&self) -> &HashMap<u8, u64> {
+        &self.counts
+    }
+
+    /// Total number of reserved header bytes seen
+    pub fn total(&self) -> u64 {
+        self.counts.values().sum()
+    }
+}
+
+/// Infers which ITM / DWT features a trace uses
+///
+/// Feed it a sample of the decoded packets; the `summary` tells which packet families are present
+/// (e.g. whether global timestamps or data tracing are enabled), which helps configure downstream
+/// tooling.
+#[derive(Clone, Debug, Default)]
+pub struct TraceProfile {
+    summary: ProfileSummary,
+}
+
+impl TraceProfile {
+    /// Creates an empty profile
+    pub fn new() -> Self {
+        TraceProfile::default()
+    }
+
+    /// Adds `packet` to the profile
+    pub fn observe(&mut self, packet: &Packet) {
+        let summary = &mut self.summary;
+        let count = match *packet {
+            Packet::Overflow => &mut summary.overflows,
+            Packet::Synchronization(_) => &mut summary.synchronization,
+            Packet::Instrumentation(_) | Packet::StimulusPortPage(_) => {
+                &mut summary.instrumentation
+            }
+            Packet::LocalTimestamp(_) => &mut summary.local_timestamps,
+            Packet::GTS1(_) | Packet::GTS2(_) => &mut summary.global_timestamps,
+            Packet::Extension(_) => &mut summary.extensions,
+            Packet::EventCounter(_) => &mut summary.event_counters,
+            Packet::ExceptionTrace(_) => &mut summary.exception_traces,
+            Packet::PeriodicPcSample(_) => &mut summary.pc_samples,
+            Packet::DataTracePcValue(_)
+            | Packet::DataTraceAddress(_)
+            | Packet::DataTraceDataValue(_) => &mut summary.data_traces,
+        };
+
+        *count += 1;
+    }
+
+    /// Number of packets of each family observed so far
+    ///
+    /// A family is in use if its count is non-zero.
+    pub fn summary(&self) -> ProfileSummary {
+        self.summary
+    }
+}
+
+/// Number of packets of each family, see `TraceProfile`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ProfileSummary {
+    /// Synchronization packets
+    pub synchronization: u64,
+    /// Overflow packets
+    pub overflows: u64,
+    /// Instrumentation and Stimulus Port Page packets (software tracing)
+    pub instrumentation: u64,
+    /// Local timestamp packets
+    pub local_timestamps: u64,
+    /// Global timestamp packets, both formats
+    pub global_timestamps: u64,
+    /// Extension packets for hardware sources
+    pub extensions: u64,
+    /// Event counter packets (DWT profiling counters)
+    pub event_counters: u64,
+    /// Exception trace packets
+    pub exception_traces: u64,
+    /// Periodic PC sample packets
+    pub pc_samples: u64,
+    /// Data trace packets, all kinds
+    pub data_traces: u64,
+}
+
+/// Collapses runs of identical consecutive packets
+///
+/// A sleeping core emits a flood of identical Periodic PC sample packets; this turns them into a
+/// single `(packet, count)` pair, which is handy for summarized output.
+#[derive(Clone, Debug, Default)]
+pub struct RunLengthEncoder {
+    run: Option<(Packet, u64)>,
+}
+
+impl RunLengthEncoder {
+    /// Creates an encoder with no pending run
+    pub fn new() -> Self {
+        RunLengthEncoder::default()
+    }
+
+    /// Adds `packet` to the current run
+    ///
+    /// Returns the previous run if `packet` is different from the packets in it.
+    pub fn observe(&mut self, packet: &Packet) -> Option<(Packet, u64)> {
+        if let Some((ref run, ref mut count)) = self.run {
+            if run == packet {
+                *count += 1;
+                return None;
+            }
+        }
+
+        self.run.replace((*packet, 1))
+    }
+
+    /// Returns the pending run, if any; call this after the last packet has been observed
+    pub fn finish(&mut self) -> Option<(Packet, u64)> {
+        self.run.take()
+    }
+}
+
+/// Annotates exception trace packets with a nearby PC value
+///
+/// Exception trace packets don't carry the PC of the interrupted code. This correlator
+/// remembers the PC of the most recent Periodic PC sample or Data trace PC value packet and
+/// attaches it to the next exception trace packet, as long as no timestamp packet came in
+/// between, i.e. both packets belong to the same timestamp batch. The PC is only an
+/// approximation of where the exception was taken: it may have been sampled several
+/// instructions earlier.
+#[derive(Clone, Debug, Default)]
+pub struct ExceptionCorrelator {
+    pc: Option<u32>,
+}
+
+impl ExceptionCorrelator {
+    /// Creates a correlator that hasn't seen any PC yet
+    pub fn new() -> Self {
+        ExceptionCorrelator::default()
+    }
+
+    /// Feeds `packet` to the correlator
+    ///
+    /// Returns an event for each exception trace packet.
+    pub fn observe(&mut self, packet: &Packet) -> Option<ExceptionEvent> {
+        match *packet {
+            Packet::PeriodicPcSample(pps) => self.pc = pps.pc(),
+            Packet::DataTracePcValue(dtpv) => self.pc = Some(dtpv.pc()),
+            Packet::LocalTimestamp(_) | Packet::GTS1(_) | Packet::GTS2(_) => self.pc = None,
+            Packet::ExceptionTrace(et) => {
+                return Some(ExceptionEvent {
+                    approx_pc: self.pc,
+                    function: et.function(),
+                    number: et.number(),
+                })
+            }
+            _ => {}
+        }
+
+        None
+    }
+}
+
+/// An exception trace packet annotated by `ExceptionCorrelator`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ExceptionEvent {
+    /// The PC of the closest preceding PC packet in the same timestamp batch, if any
+    pub approx_pc: Option<u32>,
+    /// The action taken by the processor
+    pub function: Function,
+    /// Exception number
+    pub number: u16,
+}
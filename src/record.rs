@@ -0,0 +1,164 @@
+//! Structured records sent over a stimulus port
+
+use crate::Packet;
+
+/// A field of a record
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Field {
+    signed: bool,
+    width: u8,
+}
+
+impl Field {
+    /// An unsigned little endian integer of `width` bytes
+    ///
+    /// # Panics
+    ///
+    /// This constructor panics if `width` is not in the range `1..=8`
+    pub fn unsigned(width: u8) -> Self {
+        Field::new(width, false)
+    }
+
+    /// A signed (two's complement) little endian integer of `width` bytes
+    ///
+    /// # Panics
+    ///
+    /// This constructor panics if `width` is not in the range `1..=8`
+    pub fn signed(width: u8) -> Self {
+        Field::new(width, true)
+    }
+
+    #[allow(clippy::manual_range_contains)]
+    fn new(width: u8, signed: bool) -> Self {
+        assert!(
+            width >= 1 && width <= 8,
+            "field width must be between 1 and 8 bytes"
+        );
+
+        Field { signed, width }
+    }
+
+    /// Size of this field in bytes
+    pub fn width(&self) -> u8 {
+        self.width
+    }
+
+    /// Is this a signed integer?
+    pub fn is_signed(&self) -> bool {
+        self.signed
+    }
+
+    fn decode(&self, bytes: &[u8]) -> FieldValue {
+        let value = bytes
+            .iter()
+            .rev()
+            .fold(0u64, |acc, &byte| (acc << 8) | u64::from(byte));
+
+        if self.signed {
+            // sign extend
+            let shift = 64 - 8 * u32::from(self.width);
+            FieldValue::Signed(((value << shift) as i64) >> shift)
+        } else {
+            FieldValue::Unsigned(value)
+        }
+    }
+}
+
+/// The decoded value of a record field
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FieldValue {
+    /// Value of an unsigned field
+    Unsigned(u64),
+    /// Value of a signed field
+    Signed(i64),
+}
+
+/// The layout of a fixed size binary record
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordSchema {
+    fields: Vec<Field>,
+}
+
+impl RecordSchema {
+    /// Creates a schema whose records consist of `fields`, in order, with no padding in between
+    ///
+    /// # Panics
+    ///
+    /// This constructor panics if `fields` is empty
+    pub fn new(fields: Vec<Field>) -> Self {
+        assert!(!fields.is_empty(), "a record must have at least one field");
+
+        RecordSchema { fields }
+    }
+
+    /// The fields of a record
+    pub fn fields(&self) -> &[Field] {
+        &self.fields
+    }
+
+    /// Size of a record in bytes
+    pub fn record_len(&self) -> usize {
+        self.fields.iter().map(|f| usize::from(f.width)).sum()
+    }
+}
+
+/// Reassembles the instrumentation packets of a stimulus port into records
+///
+/// The target writes fixed size records to a single stimulus port; the writes may be split
+/// across several instrumentation packets. The assembler buffers the payloads sent over its port
+/// and decodes each complete record according to the schema. Packets from other ports, and
+/// packets other than instrumentation packets, are ignored. The port is compared against
+/// `Instrumentation::paged_port`.
+#[derive(Clone, Debug)]
+pub struct RecordAssembler {
+    buffer: Vec<u8>,
+    port: u16,
+    schema: RecordSchema,
+}
+
+impl RecordAssembler {
+    /// Creates an assembler for the records sent over the stimulus `port`
+    pub fn new(port: u16, schema: RecordSchema) -> Self {
+        RecordAssembler {
+            buffer: vec![],
+            port,
+            schema,
+        }
+    }
+
+    /// Feeds `packet` to the assembler and returns the records it completed
+    pub fn feed(&mut self, packet: &Packet) -> Vec<Vec<FieldValue>> {
+        let mut records = vec![];
+
+        if let Packet::Instrumentation(i) = *packet {
+            if i.paged_port() == self.port {
+                self.buffer.extend_from_slice(i.payload());
+
+                let len = self.schema.record_len();
+                while self.buffer.len() >= len {
+                    let mut offset = 0;
+                    let record = self
+                        .schema
+                        .fields
+                        .iter()
+                        .map(|field| {
+                            let bytes = &self.buffer[offset..offset + usize::from(field.width)];
+                            offset += usize::from(field.width);
+                            field.decode(bytes)
+                        })
+                        .collect();
+
+                    self.buffer.drain(..len);
+                    records.push(record);
+                }
+            }
+        }
+
+        records
+    }
+
+    /// Number of bytes of an incomplete record that are currently buffered
+    pub fn pending(&self) -> usize {
+        self.buffer.len()
+    }
+}
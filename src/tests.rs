@@ -1,6 +1,27 @@
-use std::io::Cursor;
-
-use crate::{packet::Function, Error, Packet, Stream};
+use std::{
+    cell::Cell,
+    fs::File,
+    io::{Cursor, Read},
+    mem,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    diff::{diff_traces, TraceDiff},
+    event::TraceEvent,
+    framing::StripHeaderReader,
+    hex::HexLineReader,
+    interpret::Interpreters,
+    packet::{Access, Counter, Function},
+    profile::{
+        ExceptionCorrelator, ExceptionEvent, HeaderTally, PcProfiler, RunLengthEncoder, SleepEvent,
+        SleepTracker, TraceProfile,
+    },
+    record::{Field, FieldValue, RecordAssembler, RecordSchema},
+    BitOrderGuess, Checkpoint, Error, Observer, Packet, Stream, SyncPattern,
+};
 
 #[test]
 fn synchronization() {
@@ -31,9 +52,9 @@ fn synchronization() {
 
     // malformed
     match stream.next().unwrap().unwrap() {
-        Err(Error::MalformedPacket { header, len }) => {
-            assert_eq!(header, 0);
-            assert_eq!(len, 4);
+        Err(Error::InvalidSync { observed, required }) => {
+            assert_eq!(observed, 4);
+            assert_eq!(required, 5);
         }
         _ => panic!(),
     }
@@ -51,6 +72,242 @@ fn synchronization() {
     assert!(stream.next().unwrap().is_none());
 }
 
+#[test]
+fn synchronization_lengths() {
+    // minimal packet: 5 zero bytes (40 bits) + 7 zero bits of the terminator = 47 zero bits
+    let mut stream = Stream::new(Cursor::new(&[0, 0, 0, 0, 0, 0x80]), false);
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::Synchronization(s) => assert_eq!(s.len(), 6),
+        _ => panic!(),
+    }
+
+    // longer runs of zeros are also valid
+    let mut stream = Stream::new(Cursor::new(&[0, 0, 0, 0, 0, 0, 0, 0, 0x80]), false);
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::Synchronization(s) => assert_eq!(s.len(), 9),
+        _ => panic!(),
+    }
+
+    // 39 zero bits are not enough
+    let mut stream = Stream::new(Cursor::new(&[0, 0, 0, 0, 0x80]), false);
+
+    match stream.next().unwrap().unwrap() {
+        Err(Error::InvalidSync { observed, required }) => {
+            assert_eq!(observed, 4);
+            assert_eq!(required, 5);
+        }
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn sync_pattern() {
+    let bytes = [0, 0, 0xff, 0x70];
+
+    let mut stream = Stream::new(Cursor::new(&bytes), false);
+    stream.set_sync_pattern(SyncPattern::new(2, 0xff));
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::Synchronization(s) => assert_eq!(s.len(), 3),
+        _ => panic!(),
+    }
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::Overflow => {}
+        _ => panic!(),
+    }
+
+    // EOF
+    assert!(stream.next().unwrap().is_none());
+
+    // not a valid Synchronization packet with the default pattern
+    let mut stream = Stream::new(Cursor::new(&bytes), false);
+
+    match stream.next().unwrap().unwrap() {
+        Err(Error::InvalidSync { observed, required }) => {
+            assert_eq!(observed, 2);
+            assert_eq!(required, 5);
+        }
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn zero_padding() {
+    let bytes = [
+        // padding
+        0, //
+        // Overflow
+        0x70,
+    ];
+
+    // by default the padding is reported as a malformed Synchronization packet
+    let mut stream = Stream::new(Cursor::new(&bytes), false);
+
+    match stream.next().unwrap().unwrap() {
+        Err(Error::InvalidSync { observed, required }) => {
+            assert_eq!(observed, 1);
+            assert_eq!(required, 5);
+        }
+        _ => panic!(),
+    }
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::Overflow => {}
+        _ => panic!(),
+    }
+
+    // EOF
+    assert!(stream.next().unwrap().is_none());
+
+    let mut stream = Stream::new(Cursor::new(&bytes), false);
+    stream.set_allow_zero_padding(true);
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::Overflow => {}
+        _ => panic!(),
+    }
+
+    // EOF
+    assert!(stream.next().unwrap().is_none());
+}
+
+#[test]
+fn resync_callback() {
+    let mut stream = Stream::new(
+        Cursor::new(&[
+            // junk
+            0x04, 0x04, 0x04, //
+            // Synchronization
+            0, 0, 0, 0, 0, 0x80, //
+            // Overflow
+            0x70,
+        ]),
+        false,
+    );
+
+    let resyncs = Arc::new(Mutex::new(vec![]));
+    stream.set_resync_callback({
+        let resyncs = resyncs.clone();
+        move |discarded| resyncs.lock().unwrap().push(discarded)
+    });
+
+    for _ in 0..3 {
+        match stream.next().unwrap().unwrap() {
+            Err(Error::ReservedHeader { byte }) => assert_eq!(byte, 0x04),
+            _ => panic!(),
+        }
+    }
+    assert!(resyncs.lock().unwrap().is_empty());
+
+    assert_eq!(stream.last_sync_bits(), 0);
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::Synchronization(_) => {}
+        _ => panic!(),
+    }
+    assert_eq!(*resyncs.lock().unwrap(), [3]);
+    assert_eq!(stream.last_sync_bits(), 24);
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::Overflow => {}
+        _ => panic!(),
+    }
+
+    // EOF
+    assert!(stream.next().unwrap().is_none());
+    assert_eq!(*resyncs.lock().unwrap(), [3]);
+}
+
+#[test]
+fn last_packet() {
+    let mut stream = Stream::new(
+        Cursor::new(&[
+            // Overflow
+            0x70, //
+            // junk
+            0x04, //
+            // Stimulus Port Page
+            0x18,
+        ]),
+        false,
+    );
+
+    assert!(stream.last_packet().is_none());
+    assert_eq!(stream.packet_count(), 0);
+
+    stream.next().unwrap().unwrap().unwrap();
+    match stream.last_packet() {
+        Some(Packet::Overflow) => {}
+        _ => panic!(),
+    }
+    assert_eq!(stream.packet_count(), 1);
+
+    // malformed packets don't update the last packet
+    assert!(stream.next().unwrap().unwrap().is_err());
+    match stream.last_packet() {
+        Some(Packet::Overflow) => {}
+        _ => panic!(),
+    }
+    assert_eq!(stream.packet_count(), 1);
+
+    stream.next().unwrap().unwrap().unwrap();
+    match stream.last_packet() {
+        Some(Packet::StimulusPortPage(spp)) => assert_eq!(spp.page(), 1),
+        _ => panic!(),
+    }
+    assert_eq!(stream.packet_count(), 2);
+
+    // EOF
+    assert!(stream.next().unwrap().is_none());
+    assert_eq!(stream.packet_count(), 2);
+}
+
+#[test]
+fn reserved_headers() {
+    let bytes = [
+        // reserved: 0bAAAA_A000
+        0x88, 0x80, //
+        // Overflow
+        0x70,
+    ];
+
+    // by default reserved headers are reported
+    let mut stream = Stream::new(Cursor::new(&bytes), false);
+
+    match stream.next().unwrap().unwrap() {
+        Err(Error::ReservedHeader { byte }) => assert_eq!(byte, 0x88),
+        _ => panic!(),
+    }
+
+    match stream.next().unwrap().unwrap() {
+        Err(Error::ReservedHeader { byte }) => assert_eq!(byte, 0x80),
+        _ => panic!(),
+    }
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::Overflow => {}
+        _ => panic!(),
+    }
+
+    // EOF
+    assert!(stream.next().unwrap().is_none());
+
+    // or they can be skipped
+    let mut stream = Stream::new(Cursor::new(&bytes), false);
+    stream.set_skip_reserved_headers(true);
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::Overflow => {}
+        _ => panic!(),
+    }
+
+    // EOF
+    assert!(stream.next().unwrap().is_none());
+}
+
 #[test]
 fn overflow() {
     let mut stream = Stream::new(Cursor::new(&[0x70]), false);
@@ -64,6 +321,35 @@ fn overflow() {
     assert!(stream.next().unwrap().is_none());
 }
 
+#[test]
+fn error_on_overflow() {
+    let mut stream = Stream::new(
+        Cursor::new(&[
+            // Instrumentation
+            0x01, 0x10, //
+            // Overflow
+            0x70, //
+            // Instrumentation
+            0x01, 0x20,
+        ]),
+        false,
+    );
+    stream.set_error_on_overflow(true);
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::Instrumentation(i) => assert_eq!(i.payload(), &[0x10]),
+        _ => panic!(),
+    }
+
+    match stream.next().unwrap().unwrap() {
+        Err(Error::Overflow) => {}
+        _ => panic!(),
+    }
+
+    // the stream terminates
+    assert!(stream.next().unwrap().is_none());
+}
+
 #[test]
 fn instrumentation() {
     let mut stream = Stream::new(
@@ -106,6 +392,20 @@ fn instrumentation() {
     assert!(stream.next().unwrap().is_none());
 }
 
+#[test]
+fn no_empty_payloads() {
+    // no header byte may decode into an instrumentation or data value packet without payload
+    for header in 0..=255 {
+        let mut stream = Stream::new(Cursor::new([header, 0x01, 0x02, 0x03, 0x04]), false);
+
+        match stream.next().unwrap().unwrap() {
+            Ok(Packet::Instrumentation(i)) => assert!(!i.payload().is_empty()),
+            Ok(Packet::DataTraceDataValue(dtdv)) => assert!(!dtdv.value().is_empty()),
+            _ => {}
+        }
+    }
+}
+
 #[test]
 fn lts1() {
     let mut stream = Stream::new(
@@ -186,6 +486,43 @@ fn lts1() {
     assert!(stream.next().unwrap().is_none());
 }
 
+#[test]
+fn lts1_relation() {
+    let mut stream = Stream::new(
+        Cursor::new(&[
+            // LTS1; TC = 0b00
+            0xc0, 0x01, //
+            // LTS1; TC = 0b01
+            0xd0, 0x01, //
+            // LTS1; TC = 0b10
+            0xe0, 0x01, //
+            // LTS1; TC = 0b11
+            0xf0, 0x01,
+        ]),
+        false,
+    );
+
+    for &(precise, timestamp_delayed, event_delayed) in &[
+        (true, false, false),
+        (false, true, false),
+        (false, false, true),
+        (false, true, true),
+    ] {
+        match stream.next().unwrap().unwrap().unwrap() {
+            Packet::LocalTimestamp(lt) => {
+                assert_eq!(lt.is_precise(), precise);
+                assert_eq!(lt.is_delayed(), !precise);
+                assert_eq!(lt.timestamp_delayed(), timestamp_delayed);
+                assert_eq!(lt.event_delayed(), event_delayed);
+            }
+            _ => panic!(),
+        }
+    }
+
+    // EOF
+    assert!(stream.next().unwrap().is_none());
+}
+
 #[test]
 fn lts2() {
     let mut stream = Stream::new(
@@ -374,6 +711,53 @@ fn event_counter() {
     assert!(stream.next().unwrap().is_none());
 }
 
+#[test]
+fn event_counter_wrapped() {
+    let mut stream = Stream::new(
+        Cursor::new(&[
+            // Event Counter; CPI, LSU and POST
+            0x05, 0b10_1001, //
+            // Event Counter; nothing
+            0x05, 0x00,
+        ]),
+        false,
+    );
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::EventCounter(ec) => assert_eq!(
+            ec.wrapped().collect::<Vec<_>>(),
+            [Counter::Cpi, Counter::Lsu, Counter::Post]
+        ),
+        _ => panic!(),
+    }
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::EventCounter(ec) => assert_eq!(ec.wrapped().count(), 0),
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn counter_register_names() {
+    let mut stream = Stream::new(
+        Cursor::new(&[
+            // Event Counter; all counters
+            0x05, 0b11_1111,
+        ]),
+        false,
+    );
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::EventCounter(ec) => assert_eq!(
+            ec.wrapped()
+                .map(|counter| counter.register_name())
+                .collect::<Vec<_>>(),
+            ["CPICNT", "EXCCNT", "SLEEPCNT", "LSUCNT", "FOLDCNT", "POSTCNT"]
+        ),
+        _ => panic!(),
+    }
+}
+
 #[test]
 fn exception_trace() {
     let mut stream = Stream::new(
@@ -446,6 +830,45 @@ fn periodic_pc_sample() {
     assert!(stream.next().unwrap().is_none());
 }
 
+#[test]
+fn pc_sample_decimation() {
+    let mut stream = Stream::new(
+        Cursor::new(&[
+            // Periodic PC Sample
+            0x17, 0x01, 0x00, 0x00, 0x00, //
+            // Periodic PC Sleep
+            0x15, 0x00, //
+            // Overflow
+            0x70, //
+            // Periodic PC Sample
+            0x17, 0x03, 0x00, 0x00, 0x00, //
+            // Periodic PC Sample
+            0x17, 0x04, 0x00, 0x00, 0x00,
+        ]),
+        false,
+    );
+    stream.set_pc_sample_decimation(2);
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::PeriodicPcSample(pps) => assert_eq!(pps.pc(), None),
+        _ => panic!(),
+    }
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::Overflow => {}
+        _ => panic!(),
+    }
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::PeriodicPcSample(pps) => assert_eq!(pps.pc(), Some(4)),
+        _ => panic!(),
+    }
+
+    // EOF
+    assert!(stream.next().unwrap().is_none());
+    assert_eq!(stream.packet_count(), 3);
+}
+
 #[test]
 fn data_trace_pc_value() {
     let mut stream = Stream::new(
@@ -468,6 +891,45 @@ fn data_trace_pc_value() {
     assert!(stream.next().unwrap().is_none());
 }
 
+#[test]
+fn data_trace_headers() {
+    // the comparator and payload size of every data trace packet must agree with its header
+    for header in 0..=255u8 {
+        let mut stream = Stream::new(Cursor::new([header, 0x01, 0x02, 0x03, 0x04]), false);
+
+        let packet = match stream.next().unwrap().unwrap() {
+            Ok(packet) => packet,
+            Err(_) => continue,
+        };
+
+        let cmpn = (header >> 4) & 0b11;
+        match packet {
+            Packet::DataTracePcValue(dtpv) => {
+                assert_eq!(header & 0b1100_1111, 0b0100_0111);
+                assert_eq!(dtpv.comparator(), cmpn);
+                assert_eq!(packet.encoded_len(), 5);
+            }
+            Packet::DataTraceAddress(dta) => {
+                assert_eq!(header & 0b1100_1111, 0b0100_1110);
+                assert_eq!(dta.comparator(), cmpn);
+                assert_eq!(packet.encoded_len(), 3);
+            }
+            Packet::DataTraceDataValue(dtdv) => {
+                assert_eq!(header & 0b1100_0100, 0b1000_0100);
+                assert_eq!(dtdv.comparator(), cmpn);
+                assert_eq!(dtdv.write_access(), header & 0b1000 != 0);
+                let size = match header & 0b11 {
+                    0b01 => 1,
+                    0b10 => 2,
+                    _ => 4,
+                };
+                assert_eq!(dtdv.value().len(), size);
+            }
+            _ => {}
+        }
+    }
+}
+
 #[test]
 fn data_trace_address() {
     let mut stream = Stream::new(
@@ -512,3 +974,2155 @@ fn data_trace_data_value() {
     // EOF
     assert!(stream.next().unwrap().is_none());
 }
+
+#[test]
+fn encoded_len() {
+    let bytes: &[u8] = &[
+        // Synchronization
+        0, 0, 0, 0, 0, 0x80, //
+        // Overflow
+        0x70, //
+        // Instrumentation
+        0x13, 0x70, 0x60, 0x50, 0x40, //
+        // LTS1
+        0xc0, 0x81, 0x01, //
+        // LTS2
+        0x10, //
+        // GTS1
+        0x94, 0x81, 0x81, 0x81, 0x01, //
+        // GTS2 (48-bit)
+        0xb4, 0x81, 0x81, 0x81, 0x01, //
+        // Stimulus Port Page
+        0x08, //
+        // Event Counter
+        0x05, 0x01, //
+        // Exception Trace
+        0x0e, 0x03, 0x10, //
+        // Periodic PC Sample
+        0x17, 0x78, 0x56, 0x34, 0x12, //
+        // Periodic PC Sleep
+        0x15, 0x00, //
+        // Data Trace PC Value
+        0x47, 0x78, 0x56, 0x34, 0x12, //
+        // Data Trace Address
+        0x4e, 0x12, 0x34, //
+        // Data Trace Data Value
+        0x86, 0x12, 0x34,
+    ];
+    let mut stream = Stream::new(Cursor::new(bytes), false);
+
+    let mut total = 0;
+    let mut count = 0;
+    while let Some(packet) = stream.next().unwrap() {
+        total += packet.unwrap().encoded_len();
+        count += 1;
+    }
+
+    assert_eq!(count, 15);
+    assert_eq!(total, bytes.len());
+}
+
+#[test]
+fn send_sync() {
+    fn assert_send<T: Send>() {}
+    fn assert_sync<T: Sync>() {}
+
+    assert_send::<Stream<File>>();
+    assert_send::<Packet>();
+    assert_sync::<Packet>();
+    assert_send::<Error>();
+    assert_sync::<Error>();
+
+    // callbacks only need to be `Send`
+    let mut stream = Stream::new(std::io::empty(), false);
+    let resyncs = Cell::new(0);
+    stream.set_resync_callback(move |_| resyncs.set(resyncs.get() + 1));
+
+    struct CellObserver(Cell<u64>);
+
+    impl Observer for CellObserver {
+        fn on_packet(&mut self, _: &Packet) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    stream.set_observer(CellObserver(Cell::new(0)));
+
+    let idles = Cell::new(0);
+    stream.set_idle_callback(Duration::from_secs(1), move || idles.set(idles.get() + 1));
+
+    let progress = Cell::new(0);
+    stream.set_progress_callback(1, move |bytes| progress.set(bytes));
+}
+
+#[test]
+fn pc_profiler() {
+    let mut stream = Stream::new(
+        Cursor::new(&[
+            // Periodic PC Sample
+            0x17, 0x00, 0x10, 0x00, 0x08, //
+            // Periodic PC Sleep
+            0x15, 0x00, //
+            // Periodic PC Sample
+            0x17, 0x04, 0x10, 0x00, 0x08, //
+            // Overflow
+            0x70, //
+            // Periodic PC Sample
+            0x17, 0x00, 0x10, 0x00, 0x08, //
+            // Periodic PC Sample
+            0x17, 0x08, 0x10, 0x00, 0x08, //
+            // Periodic PC Sleep
+            0x15, 0x00,
+        ]),
+        false,
+    );
+
+    let mut profiler = PcProfiler::new();
+    while let Some(packet) = stream.next().unwrap() {
+        profiler.observe(&packet.unwrap());
+    }
+
+    assert_eq!(profiler.samples(), 6);
+    assert_eq!(profiler.sleeping(), 2);
+    assert_eq!(profiler.hits().len(), 3);
+    assert_eq!(profiler.top(2), [(0x0800_1000, 2), (0x0800_1004, 1)]);
+    assert_eq!(profiler.top(10).len(), 3);
+}
+
+#[test]
+fn comparators_seen() {
+    let mut stream = Stream::new(
+        Cursor::new(&[
+            // Data Trace PC Value; comparator 1
+            0x57, 0x78, 0x56, 0x34, 0x12, //
+            // Overflow
+            0x70, //
+            // Data Trace Address; comparator 3
+            0x7e, 0x12, 0x34, //
+            // Data Trace Data Value; comparator 1
+            0x95, 0x12,
+        ]),
+        false,
+    );
+
+    assert!(stream.comparators_seen().is_empty());
+
+    while let Some(packet) = stream.next().unwrap() {
+        packet.unwrap();
+    }
+
+    assert_eq!(
+        stream.comparators_seen().into_iter().collect::<Vec<_>>(),
+        [1, 3]
+    );
+}
+
+#[test]
+fn drain() {
+    let mut stream = Stream::new(
+        Cursor::new(&[
+            // Overflow
+            0x70, //
+            // Instrumentation
+            0x01, 0x10, //
+            // Stimulus Port Page
+            0x18, //
+            // Instrumentation (truncated)
+            0x02, 0x20,
+        ]),
+        false,
+    );
+
+    // nothing has been buffered yet
+    assert!(stream.drain().is_empty());
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::Overflow => {}
+        _ => panic!(),
+    }
+
+    // the remaining bytes were read in by `next`
+    let packets = stream.drain();
+    assert_eq!(packets.len(), 2);
+    match packets[0] {
+        Ok(Packet::Instrumentation(i)) => assert_eq!(i.payload(), &[0x10]),
+        _ => panic!(),
+    }
+    match packets[1] {
+        Ok(Packet::StimulusPortPage(spp)) => assert_eq!(spp.page(), 1),
+        _ => panic!(),
+    }
+
+    // the partial packet stays buffered
+    assert!(stream.drain().is_empty());
+    match stream.next().unwrap().unwrap() {
+        Err(Error::MalformedPacket { header, len }) => {
+            assert_eq!(header, 0x02);
+            assert_eq!(len, 2);
+        }
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn into_inner() {
+    let mut stream = Stream::new(Cursor::new(&[0x70, 0x70]), false);
+
+    stream.next().unwrap().unwrap().unwrap();
+
+    // both bytes were read in by `next`; the second one is discarded
+    let cursor = stream.into_inner();
+    assert_eq!(cursor.position(), 2);
+}
+
+#[test]
+fn hex_line_reader() {
+    let log = "\
+# captured trace
+70
+01 10 # port 0
+0x0a30 20
+
+13 70 60 50 40
+";
+    let mut stream = Stream::new(HexLineReader::new(log.as_bytes()), false);
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::Overflow => {}
+        _ => panic!(),
+    }
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::Instrumentation(i) => {
+            assert_eq!(i.port(), 0);
+            assert_eq!(i.payload(), &[0x10]);
+        }
+        _ => panic!(),
+    }
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::Instrumentation(i) => {
+            assert_eq!(i.port(), 1);
+            assert_eq!(i.payload(), &[0x30, 0x20]);
+        }
+        _ => panic!(),
+    }
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::Instrumentation(i) => {
+            assert_eq!(i.port(), 2);
+            assert_eq!(i.payload(), &[0x70, 0x60, 0x50, 0x40]);
+        }
+        _ => panic!(),
+    }
+
+    // EOF
+    assert!(stream.next().unwrap().is_none());
+
+    // malformed hex is an I/O error
+    for log in &["70 7\n", "70 +f\n", "0x+f\n"] {
+        let mut stream = Stream::new(HexLineReader::new(log.as_bytes()), false);
+        assert_eq!(
+            stream.next().unwrap_err().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+    }
+}
+
+#[test]
+fn byte_limit() {
+    let mut stream = Stream::new(
+        Cursor::new(&[
+            // Overflow
+            0x70, //
+            // Instrumentation
+            0x01, 0x10, //
+            // Overflow
+            0x70,
+        ]),
+        true,
+    );
+    stream.set_byte_limit(3);
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::Overflow => {}
+        _ => panic!(),
+    }
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::Instrumentation(i) => assert_eq!(i.payload(), &[0x10]),
+        _ => panic!(),
+    }
+
+    // doesn't keep reading past the limit
+    assert!(stream.next().is_err());
+    assert_eq!(stream.get_ref().position(), 4);
+}
+
+#[test]
+fn byte_limit_exact() {
+    let mut stream = Stream::new(
+        Cursor::new(&[
+            // Overflow
+            0x70, //
+            // Instrumentation
+            0x01, 0x10,
+        ]),
+        false,
+    );
+    stream.set_byte_limit(3);
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::Overflow => {}
+        _ => panic!(),
+    }
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::Instrumentation(i) => assert_eq!(i.payload(), &[0x10]),
+        _ => panic!(),
+    }
+
+    // input that ends at the limit is a clean EOF
+    assert!(stream.next().unwrap().is_none());
+}
+
+#[test]
+fn sleep_tracker() {
+    let mut stream = Stream::new(
+        Cursor::new(&[
+            // Periodic PC Sample
+            0x17, 0x00, 0x10, 0x00, 0x08, //
+            // Periodic PC Sleep
+            0x15, 0x00, //
+            // Periodic PC Sleep
+            0x15, 0x00, //
+            // Overflow
+            0x70, //
+            // Periodic PC Sleep
+            0x15, 0x00, //
+            // Periodic PC Sample
+            0x17, 0x00, 0x10, 0x00, 0x08, //
+            // Periodic PC Sleep
+            0x15, 0x00,
+        ]),
+        false,
+    );
+
+    let mut tracker = SleepTracker::new();
+    let mut events = vec![];
+    while let Some(packet) = stream.next().unwrap() {
+        events.extend(tracker.observe(&packet.unwrap()));
+    }
+
+    assert_eq!(
+        events,
+        [
+            SleepEvent::Sleep,
+            SleepEvent::Wake { samples: 3 },
+            SleepEvent::Sleep
+        ]
+    );
+    assert_eq!(tracker.sleeps(), 2);
+    assert!(tracker.is_sleeping());
+}
+
+#[test]
+fn header_tally() {
+    let mut stream = Stream::new(
+        Cursor::new(&[
+            0x04, //
+            // Overflow
+            0x70, //
+            0x88, 0x04, 0x80, 0x04,
+        ]),
+        false,
+    );
+
+    let mut tally = HeaderTally::new();
+    let mut packets = 0;
+    while let Some(packet) = stream.next().unwrap() {
+        match packet {
+            Ok(_) => packets += 1,
+            Err(e) => tally.observe(&e),
+        }
+    }
+
+    assert_eq!(packets, 1);
+    assert_eq!(tally.total(), 5);
+    assert_eq!(tally.counts().len(), 3);
+    assert_eq!(tally.counts()[&0x04], 3);
+    assert_eq!(tally.counts()[&0x80], 1);
+    assert_eq!(tally.counts()[&0x88], 1);
+}
+
+#[test]
+fn chained_readers() {
+    // a packet that straddles the boundary between two inputs
+    let first: &[u8] = &[0x70, 0x13, 0x70, 0x60];
+    let second: &[u8] = &[0x50, 0x40, 0x70];
+
+    let mut stream = Stream::new(first.chain(second), false);
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::Overflow => {}
+        _ => panic!(),
+    }
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::Instrumentation(i) => {
+            assert_eq!(i.port(), 2);
+            assert_eq!(i.payload(), &[0x70, 0x60, 0x50, 0x40]);
+        }
+        _ => panic!(),
+    }
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::Overflow => {}
+        _ => panic!(),
+    }
+
+    // EOF
+    assert!(stream.next().unwrap().is_none());
+}
+
+#[test]
+fn data_trace_data_value_describe() {
+    let mut stream = Stream::new(
+        Cursor::new(&[
+            // Data Trace Data Value; comparator 3, write, 2 bytes
+            0xbe, 0x34, 0x12, //
+            // Data Trace Data Value; comparator 0, read, 1 byte
+            0x85, 0x05,
+        ]),
+        false,
+    );
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::DataTraceDataValue(dtdv) => {
+            assert_eq!(dtdv.describe(), "comparator 3 wrote 0x1234")
+        }
+        _ => panic!(),
+    }
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::DataTraceDataValue(dtdv) => assert_eq!(dtdv.describe(), "comparator 0 read 0x05"),
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn reserved_bits() {
+    // the decoder is strict: packets with reserved bits set, or longer than the specification
+    // allows, are rejected
+    fn first(bytes: &[u8]) -> Result<Packet, Error> {
+        Stream::new(Cursor::new(bytes), false)
+            .next()
+            .unwrap()
+            .unwrap()
+    }
+
+    fn assert_malformed(bytes: &[u8], len: u8) {
+        match first(bytes) {
+            Err(Error::MalformedPacket { header, len: l }) => {
+                assert_eq!(header, bytes[0]);
+                assert_eq!(l, len);
+            }
+            _ => panic!("{:x?} was accepted", bytes),
+        }
+    }
+
+    // Exception Trace; reserved bits in the second payload byte
+    assert_malformed(&[0x0e, 0x10, 0x12], 1);
+    assert_malformed(&[0x0e, 0x10, 0x90], 1);
+    // Event Counter; reserved bits 7:6
+    assert_malformed(&[0x05, 0x40], 1);
+    // Periodic PC Sleep; non-zero payload
+    assert_malformed(&[0x15, 0x01], 1);
+    // GTS2; reserved bits in the last byte of the 48-bit format
+    assert_malformed(&[0xb4, 0x81, 0x81, 0x81, 0x02], 4);
+    // LTS1; continuation bit set in the last payload byte
+    assert_malformed(&[0xc0, 0x81, 0x81, 0x81, 0x81], 4);
+    // GTS1; continuation bit set in the last payload byte, which has no spare bits (bits 6:5
+    // are the Wrap and ClkCh flags)
+    assert_malformed(&[0x94, 0x81, 0x81, 0x81, 0x81], 4);
+}
+
+#[test]
+fn with_same_options() {
+    let mut stream = Stream::new(Cursor::new(&[0x70]), false);
+    stream.set_allow_zero_padding(true);
+    stream.set_skip_reserved_headers(true);
+
+    let mut other = stream.with_same_options(Cursor::new(&[0, 0x04, 0x70]));
+
+    match other.next().unwrap().unwrap().unwrap() {
+        Packet::Overflow => {}
+        _ => panic!(),
+    }
+
+    // EOF
+    assert!(other.next().unwrap().is_none());
+}
+
+#[test]
+fn run_into() {
+    let (tx, rx) = mpsc::channel();
+
+    let producer = thread::spawn(move || {
+        let mut stream = Stream::new(
+            Cursor::new(vec![
+                // Overflow
+                0x70, //
+                // junk
+                0x04, //
+                // Instrumentation
+                0x01, 0x10,
+            ]),
+            false,
+        );
+
+        stream.run_into(tx)
+    });
+
+    let packets = rx.iter().collect::<Vec<_>>();
+    producer.join().unwrap().unwrap();
+
+    assert_eq!(packets.len(), 3);
+    match packets[0] {
+        Ok(Packet::Overflow) => {}
+        _ => panic!(),
+    }
+    match packets[1] {
+        Err(Error::ReservedHeader { byte }) => assert_eq!(byte, 0x04),
+        _ => panic!(),
+    }
+    match packets[2] {
+        Ok(Packet::Instrumentation(i)) => assert_eq!(i.payload(), &[0x10]),
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn records() {
+    let mut stream = Stream::new(
+        Cursor::new(&[
+            // port 1; 4 bytes
+            0x0b, 0x01, 0x00, 0xff, 0xff, //
+            // port 0; 1 byte
+            0x01, 0x10, //
+            // port 1; 2 bytes
+            0x0a, 0x78, 0x56, //
+            // port 1; 4 bytes
+            0x0b, 0x34, 0x12, 0x02, 0x00, //
+            // port 1; 1 byte
+            0x09, 0xfe, //
+            // Stimulus port page 1
+            0x18, //
+            // port 33; 1 byte
+            0x09, 0xaa,
+        ]),
+        false,
+    );
+
+    // a `u16` followed by an `i16` and a `u32`
+    let schema = RecordSchema::new(vec![
+        Field::unsigned(2),
+        Field::signed(2),
+        Field::unsigned(4),
+    ]);
+    assert_eq!(schema.record_len(), 8);
+
+    let mut assembler = RecordAssembler::new(1, schema);
+    let mut records = vec![];
+    while let Some(packet) = stream.next().unwrap() {
+        records.extend(assembler.feed(&packet.unwrap()));
+    }
+
+    assert_eq!(
+        records,
+        [vec![
+            FieldValue::Unsigned(1),
+            FieldValue::Signed(-1),
+            FieldValue::Unsigned(0x1234_5678),
+        ]]
+    );
+    assert_eq!(assembler.pending(), 3);
+}
+
+#[test]
+fn next_with_header() {
+    let mut stream = Stream::new(
+        Cursor::new(&[
+            // Overflow
+            0x70, //
+            // Instrumentation
+            0x0a, 0x30, 0x20, //
+            // junk
+            0x04,
+        ]),
+        false,
+    );
+
+    match stream.next_with_header().unwrap().unwrap().unwrap() {
+        (0x70, Packet::Overflow) => {}
+        _ => panic!(),
+    }
+
+    match stream.next_with_header().unwrap().unwrap().unwrap() {
+        (0x0a, Packet::Instrumentation(i)) => assert_eq!(i.payload(), &[0x30, 0x20]),
+        _ => panic!(),
+    }
+
+    match stream.next_with_header().unwrap().unwrap() {
+        Err(Error::ReservedHeader { byte }) => assert_eq!(byte, 0x04),
+        _ => panic!(),
+    }
+
+    // EOF
+    assert!(stream.next_with_header().unwrap().is_none());
+}
+
+#[cfg(feature = "testutil")]
+#[test]
+fn assert_decodes_to() {
+    use crate::testutil;
+
+    let bytes = [
+        // Overflow
+        0x70, //
+        // Stimulus Port Page
+        0x18,
+    ];
+
+    let mut stream = Stream::new(Cursor::new(&bytes), false);
+    let overflow = stream.next().unwrap().unwrap().unwrap();
+    let page = stream.next().unwrap().unwrap().unwrap();
+
+    testutil::assert_decodes_to(&bytes, &[overflow, page]);
+
+    let mismatch = std::panic::catch_unwind(|| testutil::assert_decodes_to(&bytes, &[overflow]));
+    assert!(mismatch.is_err());
+}
+
+#[test]
+fn observer() {
+    #[derive(Default)]
+    struct Counts {
+        packets: usize,
+        malformed: usize,
+        resync_bits: Vec<usize>,
+        syncs: usize,
+    }
+
+    struct Counter(Arc<Mutex<Counts>>);
+
+    impl Observer for Counter {
+        fn on_packet(&mut self, _: &Packet) {
+            self.0.lock().unwrap().packets += 1;
+        }
+
+        fn on_malformed(&mut self, _: &Error) {
+            self.0.lock().unwrap().malformed += 1;
+        }
+
+        fn on_resync(&mut self, bits: usize) {
+            self.0.lock().unwrap().resync_bits.push(bits);
+        }
+
+        fn on_sync(&mut self) {
+            self.0.lock().unwrap().syncs += 1;
+        }
+    }
+
+    let mut stream = Stream::new(
+        Cursor::new(&[
+            // Synchronization
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x80, //
+            // reserved headers
+            0x04, 0x04, //
+            // Synchronization
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x80, //
+            // Overflow
+            0x70,
+        ]),
+        false,
+    );
+    let counts = Arc::new(Mutex::new(Counts::default()));
+    stream.set_observer(Counter(counts.clone()));
+
+    while stream.next().unwrap().is_some() {}
+
+    let counts = counts.lock().unwrap();
+    assert_eq!(counts.packets, 3);
+    assert_eq!(counts.malformed, 2);
+    assert_eq!(counts.resync_bits, [16]);
+    assert_eq!(counts.syncs, 2);
+}
+
+#[test]
+fn find_alignment() {
+    let mut stream = Stream::new(
+        Cursor::new(&[
+            // tail of an Instrumentation packet
+            0x04, 0x04, //
+            // Instrumentation packets
+            0x01, 0x04, //
+            0x01, 0x04, //
+            0x01, 0x04,
+        ]),
+        false,
+    );
+
+    assert_eq!(stream.find_alignment(4).unwrap(), 2);
+
+    for _ in 0..3 {
+        match stream.next().unwrap().unwrap().unwrap() {
+            Packet::Instrumentation(i) => {
+                assert_eq!(i.port(), 0);
+                assert_eq!(i.payload(), &[0x04]);
+            }
+            _ => panic!(),
+        }
+    }
+
+    assert!(stream.next().unwrap().is_none());
+
+    // the skipped bytes go through the usual accounting, and an idle `keep_reading` reader
+    // doesn't make it wait for a full buffer
+    struct Malformed(Arc<Mutex<Vec<(u8, u8)>>>);
+
+    impl Observer for Malformed {
+        fn on_malformed(&mut self, e: &Error) {
+            if let Error::MalformedPacket { header, len } = *e {
+                self.0.lock().unwrap().push((header, len));
+            }
+        }
+    }
+
+    let mut stream = Stream::new(
+        Cursor::new(&[
+            // tail of an Instrumentation packet
+            0x04, 0x04, //
+            // Instrumentation packets
+            0x01, 0x04, //
+            0x01, 0x04,
+        ]),
+        true,
+    );
+    let malformed = Arc::new(Mutex::new(vec![]));
+    stream.set_observer(Malformed(malformed.clone()));
+    let progress = Arc::new(Mutex::new(vec![]));
+    stream.set_progress_callback(1, {
+        let progress = progress.clone();
+        move |bytes| progress.lock().unwrap().push(bytes)
+    });
+
+    assert_eq!(stream.find_alignment(4).unwrap(), 2);
+    assert_eq!(*malformed.lock().unwrap(), [(0x04, 2)]);
+    assert_eq!(*progress.lock().unwrap(), [6]);
+    assert_eq!(stream.last_error_offset(), Some(0));
+    assert_eq!(stream.position(), 2);
+}
+
+#[test]
+fn encode() {
+    let bytes = [
+        // Synchronization
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x80, //
+        // Overflow
+        0x70, //
+        // Instrumentation
+        0x03, 0x01, 0x02, 0x03, 0x04, //
+        // Local timestamp (format 1 and 2)
+        0xd0, 0x81, 0x02, //
+        0x30, //
+        // GTS1
+        0x94, 0x81, 0x82, 0x83, 0x64, //
+        // GTS2 (48-bit and 64-bit)
+        0xb4, 0x81, 0x82, 0x83, 0x01, //
+        0xb4, 0x81, 0x82, 0x83, 0x84, 0x85, 0x06, //
+        // Stimulus Port Page
+        0x28, //
+        // Event counter
+        0x05, 0x21, //
+        // Exception trace
+        0x0e, 0x2a, 0x21, //
+        // Periodic PC sample (full and sleep)
+        0x17, 0x01, 0x02, 0x03, 0x04, //
+        0x15, 0x00, //
+        // Data trace PC value, address and data value
+        0x57, 0x01, 0x02, 0x03, 0x04, //
+        0x6e, 0x01, 0x02, //
+        0x9e, 0x01, 0x02,
+    ];
+
+    let mut stream = Stream::new(Cursor::new(&bytes), false);
+    let mut encoded = vec![];
+    while let Some(packet) = stream.next().unwrap() {
+        let packet = packet.unwrap();
+        let bytes = packet.encode();
+        assert_eq!(bytes.len(), packet.encoded_len());
+        encoded.extend(bytes);
+    }
+
+    assert_eq!(encoded, &bytes[..]);
+}
+
+#[test]
+fn encode_round_trip() {
+    // parse -> encode -> parse every header byte followed by pseudo-random payloads drawn from
+    // values that exercise the C (Continue) and reserved bits
+    const PAYLOAD: [u8; 9] = [0x00, 0x01, 0x02, 0x10, 0x20, 0x7f, 0x80, 0x81, 0xff];
+
+    let mut state = 0x2545_f491_u32;
+    let mut random = move || {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state
+    };
+
+    let mut inputs = vec![vec![0x00, 0x00, 0x00, 0x00, 0x00, 0x80]];
+    for header in 0..=255 {
+        for _ in 0..256 {
+            let mut input = vec![header];
+            for _ in 0..7 {
+                let r = random();
+                input.push(PAYLOAD.get(r as usize % 10).cloned().unwrap_or(r as u8));
+            }
+            inputs.push(input);
+        }
+    }
+
+    let mut kinds = vec![];
+    for input in inputs {
+        let packet = match crate::decode_chunk(&input).0[0] {
+            Ok(packet) => packet,
+            Err(_) => continue,
+        };
+
+        let encoded = packet.encode();
+        assert_eq!(encoded.len(), packet.encoded_len());
+
+        match crate::decode_chunk(&encoded) {
+            (ref packets, 0) if packets.len() == 1 => match packets[0] {
+                Ok(ref again) => assert_eq!(*again, packet, "{:02x?}", input),
+                Err(ref e) => panic!("{:02x?} re-encoded as {:02x?}: {}", input, encoded, e),
+            },
+            _ => panic!("{:02x?} re-encoded as {:02x?}", input, encoded),
+        }
+
+        let kind = mem::discriminant(&packet);
+        if !kinds.contains(&kind) {
+            kinds.push(kind);
+        }
+    }
+
+    // every packet type has been covered
+    assert_eq!(kinds.len(), 14);
+}
+
+#[test]
+fn last_error_bytes() {
+    let bytes = [
+        // Synchronization (7 zeros)
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, //
+        // Instrumentation
+        0x03, 0x61, 0x62, 0x63, 0x64, //
+        // reserved header
+        0x04, //
+        // malformed GTS2 (too many continuation bytes)
+        0xb4, 0x81, 0x81, 0x81, 0x81, 0x81, //
+        // Overflow
+        0x70, //
+        // too short Synchronization
+        0x00, 0x00, //
+        // Instrumentation
+        0x01, 0x65, //
+        // Periodic PC sample
+        0x17, 0x00, 0x01, 0x00, 0x08, //
+        // truncated Exception trace
+        0x0e, 0x0f,
+    ];
+
+    let mut stream = Stream::new(&bytes[..], false);
+    assert_eq!(stream.last_error_bytes(), []);
+
+    let mut output = vec![];
+    let mut errors = 0;
+    while let Some(packet) = stream.next().unwrap() {
+        match packet {
+            Ok(packet) => output.extend(packet.encode()),
+            Err(_) => {
+                output.extend(stream.last_error_bytes());
+                errors += 1;
+            }
+        }
+    }
+
+    assert_eq!(errors, 4);
+    assert_eq!(stream.last_error_bytes(), [0x0e, 0x0f]);
+    assert_eq!(output[..], bytes[..]);
+}
+
+#[test]
+#[allow(clippy::match_like_matches_macro)]
+fn reencode_filtered() {
+    let bytes = [
+        // Instrumentation (port 0)
+        0x01, 0x61, //
+        // reserved header
+        0x04, //
+        // Instrumentation (port 1)
+        0x09, 0x62, //
+        // Overflow
+        0x70, //
+        // Instrumentation (port 0)
+        0x02, 0x63, 0x64,
+    ];
+
+    let mut output = vec![];
+    Stream::new(Cursor::new(&bytes), false)
+        .reencode_filtered(&mut output, |packet| match packet {
+            Packet::Instrumentation(i) => i.port() == 0,
+            _ => true,
+        })
+        .unwrap();
+
+    assert_eq!(output, [0x01, 0x61, 0x70, 0x02, 0x63, 0x64]);
+
+    let mut stream = Stream::new(Cursor::new(&output), false);
+    let mut packets = vec![];
+    while let Some(packet) = stream.next().unwrap() {
+        packets.push(packet.unwrap());
+    }
+
+    match packets[..] {
+        [Packet::Instrumentation(a), Packet::Overflow, Packet::Instrumentation(b)] => {
+            assert_eq!(a.payload(), b"a");
+            assert_eq!(b.payload(), b"cd");
+        }
+        _ => panic!(),
+    }
+
+    // stimulus port pages survive even if `keep` drops the StimulusPortPage packets
+    let bytes = [
+        // Stimulus Port Page 1
+        0x18, //
+        // Instrumentation (port 1)
+        0x09, 0x62, //
+        // Stimulus Port Page 0
+        0x08, //
+        // Instrumentation (port 0)
+        0x01, 0x61,
+    ];
+
+    let mut output = vec![];
+    Stream::new(Cursor::new(&bytes), false)
+        .reencode_filtered(&mut output, |packet| match packet {
+            Packet::StimulusPortPage(_) => false,
+            _ => true,
+        })
+        .unwrap();
+
+    assert_eq!(output, bytes);
+
+    let (packets, _) = crate::decode_chunk(&output);
+    let ports = packets
+        .iter()
+        .filter_map(|packet| match *packet {
+            Ok(Packet::Instrumentation(i)) => Some(i.paged_port()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(ports, [33, 0]);
+}
+
+#[test]
+fn error_offset() {
+    let mut stream = Stream::new(
+        Cursor::new(&[
+            // Overflow
+            0x70, //
+            // Instrumentation
+            0x01, 0x61, //
+            // reserved header
+            0x04, //
+            // Overflow
+            0x70, //
+            // truncated Instrumentation
+            0x02, 0x62,
+        ]),
+        false,
+    );
+
+    assert_eq!(stream.position(), 0);
+    assert_eq!(stream.last_error_offset(), None);
+
+    stream.next().unwrap().unwrap().unwrap();
+    stream.next().unwrap().unwrap().unwrap();
+    assert_eq!(stream.position(), 3);
+
+    assert!(stream.next().unwrap().unwrap().is_err());
+    assert_eq!(stream.last_error_offset(), Some(3));
+
+    stream.next().unwrap().unwrap().unwrap();
+    assert_eq!(stream.position(), 5);
+
+    assert!(stream.next().unwrap().unwrap().is_err());
+    assert_eq!(stream.last_error_offset(), Some(5));
+}
+
+#[test]
+fn interpreters() {
+    #[derive(Debug, PartialEq)]
+    enum Payload {
+        Text(String),
+        Reading(u16),
+    }
+
+    let mut interpreters = Interpreters::new();
+    interpreters.register(0, |bytes| {
+        Payload::Text(String::from_utf8_lossy(bytes).into_owned())
+    });
+    interpreters.register(1, |bytes| {
+        Payload::Reading(u16::from(bytes[0]) | (u16::from(bytes[1]) << 8))
+    });
+    interpreters.register(32, |bytes| {
+        Payload::Text(String::from_utf8_lossy(bytes).into_owned())
+    });
+
+    let mut stream = Stream::new(
+        Cursor::new(&[
+            // Instrumentation (port 0)
+            0x02, 0x68, 0x69, //
+            // Instrumentation (port 1)
+            0x0a, 0x34, 0x12, //
+            // Instrumentation (port 2)
+            0x11, 0x00, //
+            // Stimulus port page 1
+            0x18, //
+            // Instrumentation (port 32)
+            0x02, 0x68, 0x69, //
+            // Instrumentation (port 33)
+            0x0a, 0x34, 0x12, //
+            // Overflow
+            0x70,
+        ]),
+        false,
+    );
+
+    let mut payloads = vec![];
+    while let Some(packet) = stream.next().unwrap() {
+        payloads.push(interpreters.interpret(&packet.unwrap()));
+    }
+
+    assert_eq!(
+        payloads,
+        [
+            Some(Payload::Text("hi".to_owned())),
+            Some(Payload::Reading(0x1234)),
+            None,
+            None,
+            Some(Payload::Text("hi".to_owned())),
+            None,
+            None,
+        ]
+    );
+}
+
+#[test]
+fn diff() {
+    fn decode(bytes: &[u8]) -> Vec<Packet> {
+        let mut stream = Stream::new(Cursor::new(bytes), false);
+        let mut packets = vec![];
+        while let Some(packet) = stream.next().unwrap() {
+            packets.push(packet.unwrap());
+        }
+        packets
+    }
+
+    // Instrumentation packets 'a', 'b' and 'c' plus timestamps
+    let good = decode(&[0x01, 0x61, 0x10, 0x01, 0x62, 0x01, 0x63]);
+    let a = good[0];
+    let b = good[2];
+    let c = good[3];
+
+    // identical modulo timestamps
+    let same = decode(&[0x01, 0x61, 0x01, 0x62, 0x20, 0x01, 0x63]);
+    assert!(diff_traces(good.clone(), same).is_empty());
+
+    // reordered
+    let reordered = decode(&[0x01, 0x62, 0x01, 0x61, 0x01, 0x63]);
+    assert_eq!(
+        diff_traces(good.clone(), reordered),
+        [
+            TraceDiff::Delete {
+                index: 0,
+                packet: a
+            },
+            TraceDiff::Insert {
+                index: 3,
+                packet: a
+            },
+        ]
+    );
+
+    // divergent: 'c' became an Overflow and there's an extra 'b' at the end
+    let divergent = decode(&[0x01, 0x61, 0x01, 0x62, 0x70, 0x01, 0x62]);
+    assert_eq!(
+        diff_traces(good, divergent),
+        [
+            TraceDiff::Replace {
+                index: 3,
+                old: c,
+                new: Packet::Overflow
+            },
+            TraceDiff::Insert {
+                index: 4,
+                packet: b
+            },
+        ]
+    );
+}
+
+#[test]
+fn extension() {
+    let mut stream = Stream::new(
+        Cursor::new(&[
+            // Extension, SH = 0
+            0x58, //
+            // Extension, SH = 1
+            0x5c,
+        ]),
+        false,
+    );
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::StimulusPortPage(spp) => assert_eq!(spp.page(), 5),
+        _ => panic!(),
+    }
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::Extension(e) => assert_eq!(e.info(), 5),
+        _ => panic!(),
+    }
+
+    // the specification allows Extension packets to continue (C bit set) but multi-byte
+    // Extension packets aren't decoded; the header is reported as reserved
+    let mut stream = Stream::new(Cursor::new(&[0x88]), false);
+    match stream.next().unwrap().unwrap() {
+        Err(Error::ReservedHeader { byte }) => assert_eq!(byte, 0x88),
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn idle_callback() {
+    // returns no data `stalls` times, then an Overflow packet
+    struct Stalling {
+        stalls: usize,
+    }
+
+    impl Read for Stalling {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.stalls == 0 {
+                buf[0] = 0x70;
+                Ok(1)
+            } else {
+                self.stalls -= 1;
+                thread::sleep(Duration::from_millis(5));
+                Ok(0)
+            }
+        }
+    }
+
+    let idle = Arc::new(Mutex::new(0));
+    let mut stream = Stream::new(Stalling { stalls: 10 }, true);
+    let idle2 = idle.clone();
+    stream.set_idle_callback(Duration::from_millis(20), move || {
+        *idle2.lock().unwrap() += 1
+    });
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::Overflow => {}
+        _ => panic!(),
+    }
+
+    assert!(*idle.lock().unwrap() >= 1);
+}
+
+#[test]
+fn finish() {
+    // returns `bytes` and then would block forever
+    struct NonBlocking {
+        bytes: &'static [u8],
+    }
+
+    impl Read for NonBlocking {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.bytes.is_empty() {
+                Err(std::io::ErrorKind::WouldBlock.into())
+            } else {
+                self.bytes.read(buf)
+            }
+        }
+    }
+
+    // clean boundary
+    let mut stream = Stream::new(NonBlocking { bytes: &[0x70] }, false);
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::Overflow => {}
+        _ => panic!(),
+    }
+    assert!(stream.next().is_err());
+    assert!(stream.finish().is_ok());
+    assert!(stream.next().unwrap().is_none());
+
+    // mid-packet
+    let mut stream = Stream::new(
+        NonBlocking {
+            bytes: &[0x70, 0x02, 0x61],
+        },
+        false,
+    );
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::Overflow => {}
+        _ => panic!(),
+    }
+    assert!(stream.next().is_err());
+    match stream.finish() {
+        Err(Error::MalformedPacket { header, len }) => {
+            assert_eq!(header, 0x02);
+            assert_eq!(len, 2);
+        }
+        _ => panic!(),
+    }
+    assert!(stream.next().unwrap().is_none());
+}
+
+#[test]
+fn guess_bit_order() {
+    let sample: [u8; 21] = [
+        // Synchronization
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x80, //
+        // Exception trace
+        0x0e, 0x0f, 0x10, //
+        // Periodic PC sample
+        0x17, 0x00, 0x01, 0x00, 0x08, //
+        // Exception trace
+        0x0e, 0x0f, 0x20, //
+        // Periodic PC sleep
+        0x15, 0x00, //
+        // Event counter
+        0x05, 0x04,
+    ];
+    let reversed = sample
+        .iter()
+        .map(|&byte| crate::reverse_bits(byte))
+        .collect::<Vec<_>>();
+    assert_eq!(reversed[..6], [0x00, 0x00, 0x00, 0x00, 0x00, 0x01]);
+    assert_eq!(reversed[6..9], [0x70, 0xf0, 0x08]);
+
+    assert_eq!(crate::guess_bit_order(&sample), BitOrderGuess::Normal);
+    assert_eq!(crate::guess_bit_order(&reversed), BitOrderGuess::Reversed);
+    assert_eq!(crate::guess_bit_order(&[]), BitOrderGuess::Unknown);
+
+    // untrusted samples must not panic: zeros look the same in both bit orders
+    assert_eq!(crate::guess_bit_order(&[0; 300]), BitOrderGuess::Unknown);
+    let mut gts2 = vec![0xb4];
+    gts2.extend_from_slice(&[0xff; 20]);
+    crate::guess_bit_order(&gts2);
+}
+
+#[test]
+fn lenient_gts2() {
+    let bytes = [
+        // GTS2 with a 5-byte payload
+        0xb4, 0x81, 0x82, 0x83, 0x84, 0x05, //
+        // Overflow
+        0x70,
+    ];
+
+    let mut stream = Stream::new(Cursor::new(&bytes), false);
+    match stream.next().unwrap().unwrap() {
+        Err(Error::MalformedPacket { header, len }) => {
+            assert_eq!(header, 0xb4);
+            assert_eq!(len, 5);
+        }
+        _ => panic!(),
+    }
+
+    let mut stream = Stream::new(Cursor::new(&bytes), false);
+    stream.set_lenient_gts2(true);
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::GTS2(gt) => {
+            assert!(gt.is_approximate());
+            assert!(gt.is_64_bit());
+            assert_eq!(gt.bits(), 1 | (2 << 7) | (3 << 14) | (4 << 21) | (5 << 28));
+        }
+        _ => panic!(),
+    }
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::Overflow => {}
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn decode_chunk() {
+    let bytes = [
+        // Instrumentation
+        0x02, 0x61, 0x62, //
+        // Periodic PC sample
+        0x17, 0x01, 0x02, 0x03, 0x04, //
+        // Overflow
+        0x70,
+    ];
+
+    // split in the middle of the Periodic PC sample
+    let (first, leftover) = crate::decode_chunk(&bytes[..5]);
+    assert_eq!(first.len(), 1);
+    assert_eq!(leftover, 2);
+
+    let mut next = bytes[5 - leftover..5].to_vec();
+    next.extend_from_slice(&bytes[5..]);
+    let (second, leftover) = crate::decode_chunk(&next);
+    assert_eq!(leftover, 0);
+
+    match second[..] {
+        [Ok(Packet::PeriodicPcSample(pps)), Ok(Packet::Overflow)] => {
+            assert_eq!(pps.pc(), Some(0x0403_0201));
+        }
+        _ => panic!(),
+    }
+
+    // stimulus port pages are tracked within the chunk
+    let (packets, _) = crate::decode_chunk(&[0x18, 0x09, 0x00]);
+    match packets[..] {
+        [Ok(Packet::StimulusPortPage(_)), Ok(Packet::Instrumentation(i))] => {
+            assert_eq!(i.paged_port(), 33);
+        }
+        _ => panic!(),
+    }
+
+    // zero runs longer than 255 bytes are reported in chunks of 255 bytes
+    let mut bytes = vec![0; 300];
+    bytes.push(0x70);
+    let (packets, leftover) = crate::decode_chunk(&bytes);
+    assert_eq!(leftover, 0);
+    let observed = packets
+        .iter()
+        .map(|packet| match *packet {
+            Err(Error::InvalidSync { observed, .. }) => observed,
+            _ => 0,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(observed, [255, 45, 0]);
+
+    // GTS2 payloads are at most 6 bytes long
+    let mut bytes = vec![0xb4];
+    bytes.extend_from_slice(&[0xff; 20]);
+    let (packets, _) = crate::decode_chunk(&bytes);
+    match packets[0] {
+        Err(Error::MalformedPacket { header, len }) => {
+            assert_eq!(header, 0xb4);
+            assert_eq!(len, 6);
+        }
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn paged_port() {
+    let mut stream = Stream::new(
+        Cursor::new(&[
+            // Instrumentation (port 1)
+            0x09, 0x00, //
+            // Stimulus Port Page 1
+            0x18, //
+            // Instrumentation (port 1)
+            0x09, 0x00, //
+            // Stimulus Port Page 7
+            0x78, //
+            // Instrumentation (port 31)
+            0xf9, 0x00, //
+            // Overflow
+            0x70, //
+            // Instrumentation (port 31)
+            0xf9, 0x00, //
+            // Synchronization
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x80, //
+            // Instrumentation (port 31)
+            0xf9, 0x00,
+        ]),
+        false,
+    );
+
+    let mut ports = vec![];
+    while let Some(packet) = stream.next().unwrap() {
+        if let Packet::Instrumentation(i) = packet.unwrap() {
+            ports.push(i.paged_port());
+        }
+    }
+
+    assert_eq!(ports, [1, 33, 255, 255, 31]);
+}
+
+#[test]
+fn port_and_comparator() {
+    let mut stream = Stream::new(
+        Cursor::new(&[
+            // Instrumentation (port 3)
+            0x19, 0x00, //
+            // Data trace PC value (comparator 1)
+            0x57, 0x01, 0x02, 0x03, 0x04, //
+            // Data trace address (comparator 2)
+            0x6e, 0x01, 0x02, //
+            // Data trace data value (comparator 3)
+            0xb5, 0x01, //
+            // Overflow
+            0x70,
+        ]),
+        false,
+    );
+
+    let mut packets = vec![];
+    while let Some(packet) = stream.next().unwrap() {
+        let packet = packet.unwrap();
+        packets.push((packet.port(), packet.comparator()));
+    }
+
+    assert_eq!(
+        packets,
+        [
+            (Some(3), None),
+            (None, Some(1)),
+            (None, Some(2)),
+            (None, Some(3)),
+            (None, None),
+        ]
+    );
+}
+
+#[test]
+fn collapse_exception_returns() {
+    let bytes = [
+        // Exception trace; enter, exit and return
+        0x0e, 0x0f, 0x10, //
+        0x0e, 0x0f, 0x20, //
+        0x0e, 0x0f, 0x30,
+    ];
+
+    let functions = |collapse| {
+        let mut stream = Stream::new(Cursor::new(&bytes), false);
+        stream.set_collapse_exception_returns(collapse);
+
+        let mut functions = vec![];
+        while let Some(packet) = stream.next().unwrap() {
+            match packet.unwrap() {
+                Packet::ExceptionTrace(et) => functions.push(et.function()),
+                _ => panic!(),
+            }
+        }
+        functions
+    };
+
+    assert_eq!(
+        functions(false),
+        [Function::Enter, Function::Exit, Function::Return]
+    );
+    assert_eq!(
+        functions(true),
+        [Function::Enter, Function::Exit, Function::Exit]
+    );
+}
+
+#[cfg(feature = "testutil")]
+#[test]
+fn trace_builder() {
+    use crate::testutil::TraceBuilder;
+
+    let bytes = TraceBuilder::new()
+        .sync()
+        .page(1)
+        .instrumentation(2, b"ab")
+        .lts1(1_000, 0b01)
+        .lts2(3)
+        .gts1(0x3ff_ffff)
+        .gts2(0x12_3456, false)
+        .exception(300, Function::Return)
+        .pc_sample(Some(0x0800_0000))
+        .pc_sample(None)
+        .overflow()
+        .build();
+
+    let mut stream = Stream::new(Cursor::new(&bytes), false);
+    let mut next = || stream.next().unwrap().unwrap().unwrap();
+
+    match (next(), next(), next()) {
+        (Packet::Synchronization(_), Packet::StimulusPortPage(spp), Packet::Instrumentation(i)) => {
+            assert_eq!(spp.page(), 1);
+            assert_eq!(i.paged_port(), 34);
+            assert_eq!(i.payload(), b"ab");
+        }
+        _ => panic!(),
+    }
+
+    match (next(), next()) {
+        (Packet::LocalTimestamp(lts1), Packet::LocalTimestamp(lts2)) => {
+            assert_eq!(lts1.delta(), 1_000);
+            assert!(lts1.timestamp_delayed());
+            assert_eq!(lts2.delta(), 3);
+        }
+        _ => panic!(),
+    }
+
+    match (next(), next()) {
+        (Packet::GTS1(gts1), Packet::GTS2(gts2)) => {
+            assert_eq!(gts1.bits(), 0x3ff_ffff);
+            assert_eq!(gts2.bits(), 0x12_3456);
+            assert!(!gts2.is_64_bit());
+        }
+        _ => panic!(),
+    }
+
+    match (next(), next(), next(), next()) {
+        (
+            Packet::ExceptionTrace(et),
+            Packet::PeriodicPcSample(full),
+            Packet::PeriodicPcSample(sleep),
+            Packet::Overflow,
+        ) => {
+            assert_eq!(et.number(), 300);
+            assert_eq!(et.function(), Function::Return);
+            assert_eq!(full.pc(), Some(0x0800_0000));
+            assert_eq!(sleep.pc(), None);
+        }
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn next_with_recv_time() {
+    let mut stream = Stream::new(
+        Cursor::new(&[
+            // Overflow
+            0x70, //
+            // Stimulus Port Page
+            0x18, //
+            // Overflow
+            0x70,
+        ]),
+        false,
+    );
+
+    let mut times = vec![];
+    while let Some(packet) = stream.next_with_recv_time().unwrap() {
+        times.push(packet.unwrap().1);
+    }
+
+    assert_eq!(times.len(), 3);
+    assert!(times.windows(2).all(|pair| pair[0] <= pair[1]));
+}
+
+#[test]
+fn unexpected_eof_error() {
+    let bytes = [
+        // Overflow
+        0x70, //
+        // truncated Local timestamp (format 1)
+        0xc0, 0x81,
+    ];
+
+    let mut stream = Stream::new(Cursor::new(&bytes), false);
+    stream.next().unwrap().unwrap().unwrap();
+    match stream.next().unwrap().unwrap() {
+        Err(Error::MalformedPacket { header, len }) => {
+            assert_eq!(header, 0xc0);
+            assert_eq!(len, 2);
+        }
+        _ => panic!(),
+    }
+
+    let mut stream = Stream::new(Cursor::new(&bytes), false);
+    stream.set_unexpected_eof_error(true);
+    stream.next().unwrap().unwrap().unwrap();
+    match stream.next().unwrap().unwrap() {
+        Err(Error::UnexpectedEof { bytes }) => assert_eq!(bytes, [0xc0, 0x81]),
+        _ => panic!(),
+    }
+    assert!(stream.next().unwrap().is_none());
+}
+
+#[test]
+fn comparator_mask() {
+    let mut stream = Stream::new(
+        Cursor::new(&[
+            // Data trace PC value (comparator 1)
+            0x57, 0x01, 0x02, 0x03, 0x04, //
+            // Data trace address (comparator 2)
+            0x6e, 0x01, 0x02, //
+            // Data trace data value (comparator 1)
+            0x95, 0x01, //
+            // Overflow
+            0x70,
+        ]),
+        false,
+    );
+    stream.set_comparator_mask(0b0100);
+
+    let mut packets = vec![];
+    while let Some(packet) = stream.next().unwrap() {
+        packets.push(packet.unwrap());
+    }
+
+    match packets[..] {
+        [Packet::DataTraceAddress(dta), Packet::Overflow] => assert_eq!(dta.comparator(), 2),
+        _ => panic!(),
+    }
+    assert_eq!(
+        stream.comparators_seen().into_iter().collect::<Vec<_>>(),
+        [1, 2]
+    );
+}
+
+#[test]
+fn trace_profile() {
+    let mut stream = Stream::new(
+        Cursor::new(&[
+            // Synchronization
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x80, //
+            // GTS1
+            0x94, 0x01, //
+            // GTS2
+            0xb4, 0x81, 0x82, 0x83, 0x01, //
+            // Data trace address
+            0x6e, 0x01, 0x02, //
+            // Data trace data value
+            0x95, 0x01, //
+            // Instrumentation
+            0x01, 0x61,
+        ]),
+        false,
+    );
+
+    let mut profile = TraceProfile::new();
+    while let Some(packet) = stream.next().unwrap() {
+        profile.observe(&packet.unwrap());
+    }
+
+    let summary = profile.summary();
+    assert_eq!(summary.synchronization, 1);
+    assert_eq!(summary.global_timestamps, 2);
+    assert_eq!(summary.data_traces, 2);
+    assert_eq!(summary.instrumentation, 1);
+    assert_eq!(summary.local_timestamps, 0);
+    assert_eq!(summary.exception_traces, 0);
+    assert_eq!(summary.pc_samples, 0);
+}
+
+#[test]
+fn overflow_next_to_data_trace_pc_value() {
+    // 0x70 = 0b0111_0000 and 0x77 = 0b0111_0111
+    let mut stream = Stream::new(
+        Cursor::new(&[
+            // Overflow
+            0x70, //
+            // Data trace PC value (comparator 3)
+            0x77, 0x01, 0x02, 0x03, 0x04, //
+            // Overflow
+            0x70,
+        ]),
+        false,
+    );
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::Overflow => {}
+        _ => panic!(),
+    }
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::DataTracePcValue(dtpv) => {
+            assert_eq!(dtpv.comparator(), 3);
+            assert_eq!(dtpv.pc(), 0x0403_0201);
+        }
+        _ => panic!(),
+    }
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::Overflow => {}
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn next_data_value() {
+    let bytes = [
+        // Data trace data value (comparator 2, write)
+        0xad, 0x01, //
+        // Data trace data value (comparator 1, write)
+        0x9d, 0x02, //
+        // Data trace data value (comparator 2, read)
+        0xa5, 0x03, //
+        // Data trace address (comparator 2)
+        0x6e, 0x01, 0x02, //
+        // Data trace data value (comparator 2, write)
+        0xad, 0x04,
+    ];
+
+    let values = |access| {
+        let mut stream = Stream::new(Cursor::new(&bytes), false);
+        let mut values = vec![];
+        while let Some(dtdv) = stream.next_data_value(2, access).unwrap() {
+            assert_eq!(dtdv.comparator(), 2);
+            values.push(dtdv.value()[0]);
+        }
+        values
+    };
+
+    assert_eq!(values(None), [1, 3, 4]);
+    assert_eq!(values(Some(Access::Write)), [1, 4]);
+    assert_eq!(values(Some(Access::Read)), [3]);
+}
+
+#[test]
+fn progress_callback() {
+    // 100 Overflow packets
+    let bytes = [0x70; 100];
+
+    // tiny reads to get many progress reports
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = buf.len().min(7);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    let reports = Arc::new(Mutex::new(vec![]));
+    let mut stream = Stream::new(Trickle(&bytes), false);
+    let reports2 = reports.clone();
+    stream.set_progress_callback(16, move |read| reports2.lock().unwrap().push(read));
+
+    while stream.next().unwrap().is_some() {}
+
+    let reports = reports.lock().unwrap();
+    assert!(reports.len() > 1);
+    assert!(reports.windows(2).all(|pair| pair[0] < pair[1]));
+    assert_eq!(reports.last(), Some(&100));
+}
+
+#[test]
+fn truncated_slice() {
+    // Overflow followed by a truncated Periodic PC sample
+    let bytes: &[u8] = &[0x70, 0x17, 0x01, 0x02];
+
+    let mut stream = Stream::new(bytes, false);
+    stream.set_unexpected_eof_error(true);
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::Overflow => {}
+        _ => panic!(),
+    }
+
+    match stream.next().unwrap().unwrap() {
+        Err(Error::UnexpectedEof { bytes }) => assert_eq!(bytes, [0x17, 0x01, 0x02]),
+        _ => panic!(),
+    }
+
+    assert!(stream.next().unwrap().is_none());
+}
+
+#[test]
+fn run_length_encoder() {
+    let mut stream = Stream::new(
+        Cursor::new(&[
+            // Periodic PC sleep x3
+            0x15, 0x00, //
+            0x15, 0x00, //
+            0x15, 0x00, //
+            // Periodic PC sample
+            0x17, 0x01, 0x02, 0x03, 0x04, //
+            // Periodic PC sleep x2
+            0x15, 0x00, //
+            0x15, 0x00,
+        ]),
+        false,
+    );
+
+    let mut rle = RunLengthEncoder::new();
+    let mut runs = vec![];
+    while let Some(packet) = stream.next().unwrap() {
+        runs.extend(rle.observe(&packet.unwrap()));
+    }
+    runs.extend(rle.finish());
+
+    let runs = runs
+        .into_iter()
+        .map(|(packet, count)| match packet {
+            Packet::PeriodicPcSample(pps) => (pps.pc(), count),
+            _ => panic!(),
+        })
+        .collect::<Vec<_>>();
+
+    assert_eq!(runs, [(None, 3), (Some(0x0403_0201), 1), (None, 2)]);
+}
+
+#[test]
+fn strip_header_reader() {
+    // 6-byte chunks with a 2-byte sequence header
+    let bytes = [
+        0x00, 0x00, // header
+        0x01, 0x61, 0x01, 0x62, //
+        0x01, 0x00, // header
+        0x02, 0x63, 0x64, 0x70, //
+        0x02, 0x00, // header
+        0x70,
+    ];
+
+    let mut stream = Stream::new(StripHeaderReader::new(Cursor::new(&bytes), 6, 2), false);
+    let mut packets = vec![];
+    while let Some(packet) = stream.next().unwrap() {
+        packets.push(packet.unwrap());
+    }
+
+    assert_eq!(
+        packets.iter().flat_map(Packet::encode).collect::<Vec<_>>(),
+        [0x01, 0x61, 0x01, 0x62, 0x02, 0x63, 0x64, 0x70, 0x70]
+    );
+}
+
+#[test]
+fn state_eq() {
+    let bytes = [
+        // Synchronization
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x80, //
+        // Stimulus Port Page
+        0x18, //
+        // reserved header
+        0x04, //
+        // Instrumentation
+        0x02, 0x61, 0x62, //
+        // truncated Periodic PC sample
+        0x17, 0x01,
+    ];
+
+    let mut a = Stream::new(Cursor::new(&bytes), false);
+    let mut b = Stream::new(&bytes[..], false);
+    assert!(a.state_eq(&b));
+
+    a.next().unwrap();
+    assert!(!a.state_eq(&b));
+
+    while a.next().unwrap().is_some() {}
+    while b.next().unwrap().is_some() {}
+    assert!(a.state_eq(&b));
+}
+
+#[test]
+fn checkpoint() {
+    let bytes = [
+        // Stimulus Port Page
+        0x38, //
+        // Instrumentation
+        0x01, 0x61, //
+        // reserved header
+        0x04, //
+        // Instrumentation
+        0x02, 0x62, 0x63, //
+        // Periodic PC sample
+        0x17, 0x00, 0x01, 0x00, 0x08,
+    ];
+
+    let mut uninterrupted = Stream::new(&bytes[..], false);
+    let mut expected = vec![];
+    while let Some(packet) = uninterrupted.next().unwrap() {
+        expected.push(packet.map_err(|e| e.to_string()));
+    }
+
+    let mut first = Stream::new(&bytes[..], false);
+    let mut packets = vec![];
+    for _ in 0..2 {
+        packets.push(first.next().unwrap().unwrap().map_err(|e| e.to_string()));
+    }
+    // every byte has been read but only the first two packets have been decoded
+    let checkpoint = first.checkpoint();
+    assert_eq!(checkpoint.position(), 3);
+    assert_ne!(checkpoint, Checkpoint::default());
+
+    let mut resumed =
+        Stream::from_checkpoint(&bytes[checkpoint.position() as usize..], false, &checkpoint);
+    assert_eq!(resumed.checkpoint(), checkpoint);
+    while let Some(packet) = resumed.next().unwrap() {
+        packets.push(packet.map_err(|e| e.to_string()));
+    }
+
+    assert_eq!(packets, expected);
+    assert_eq!(resumed.last_error_offset(), Some(3));
+    assert!(resumed.state_eq(&uninterrupted));
+}
+
+#[test]
+fn exception_correlator() {
+    let mut stream = Stream::new(
+        Cursor::new(&[
+            // Periodic PC sample
+            0x17, 0x00, 0x01, 0x00, 0x08, //
+            // Exception trace; enter 15
+            0x0e, 0x0f, 0x10, //
+            // Local timestamp
+            0x10, //
+            // Exception trace; exit 15
+            0x0e, 0x0f, 0x20,
+        ]),
+        false,
+    );
+
+    let mut correlator = ExceptionCorrelator::new();
+    let mut events = vec![];
+    while let Some(packet) = stream.next().unwrap() {
+        events.extend(correlator.observe(&packet.unwrap()));
+    }
+
+    assert_eq!(
+        events,
+        [
+            ExceptionEvent {
+                approx_pc: Some(0x0800_0100),
+                function: Function::Enter,
+                number: 15,
+            },
+            ExceptionEvent {
+                approx_pc: None,
+                function: Function::Exit,
+                number: 15,
+            },
+        ]
+    );
+}
+
+#[test]
+fn hexdump() {
+    let mut stream = Stream::new(
+        Cursor::new(&[
+            // Instrumentation
+            0x03, 0x68, 0x69, 0x21, 0x0a,
+        ]),
+        false,
+    );
+
+    assert_eq!(
+        stream.next().unwrap().unwrap().unwrap().hexdump(),
+        "00000000  03 68 69 21 0a                                    |.hi!.|\n\
+         00000005\n"
+    );
+}
+
+#[test]
+fn pump_for() {
+    // an endless, slow stream of Overflow packets
+    struct Slow;
+
+    impl Read for Slow {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            thread::sleep(Duration::from_millis(1));
+            buf[0] = 0x70;
+            Ok(1)
+        }
+    }
+
+    let mut stream = Stream::new(Slow, false);
+    let start = std::time::Instant::now();
+    let packets = stream.pump_for(Duration::from_millis(20)).unwrap();
+    let elapsed = start.elapsed();
+
+    assert!(!packets.is_empty());
+    assert!(elapsed >= Duration::from_millis(20));
+    assert!(elapsed < Duration::from_secs(1));
+
+    // EOF ends the pump early
+    let mut stream = Stream::new(Cursor::new(&[0x70, 0x70]), false);
+    assert_eq!(stream.pump_for(Duration::from_secs(60)).unwrap().len(), 2);
+
+    // so does `WouldBlock`, without losing the packets decoded so far
+    struct NonBlocking {
+        bytes: &'static [u8],
+    }
+
+    impl Read for NonBlocking {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.bytes.is_empty() {
+                Err(std::io::ErrorKind::WouldBlock.into())
+            } else {
+                self.bytes.read(buf)
+            }
+        }
+    }
+
+    let mut stream = Stream::new(
+        NonBlocking {
+            bytes: &[0x70, 0x70, 0x01],
+        },
+        false,
+    );
+    assert_eq!(stream.pump_for(Duration::from_secs(60)).unwrap().len(), 2);
+    assert!(stream.pump_for(Duration::from_secs(60)).unwrap().is_empty());
+
+    // other I/O errors are only reported once no packets are pending
+    struct Failing {
+        bytes: &'static [u8],
+    }
+
+    impl Read for Failing {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.bytes.is_empty() {
+                Err(std::io::ErrorKind::BrokenPipe.into())
+            } else {
+                self.bytes.read(buf)
+            }
+        }
+    }
+
+    let mut stream = Stream::new(Failing { bytes: &[0x70] }, false);
+    assert_eq!(stream.pump_for(Duration::from_secs(60)).unwrap().len(), 1);
+    assert!(stream.pump_for(Duration::from_secs(60)).is_err());
+
+    // with `keep_reading` set, no data (`Ok(0)`) ends the pump instead of retrying in a loop
+    struct Empty<'a> {
+        reads: &'a Cell<usize>,
+    }
+
+    impl Read for Empty<'_> {
+        fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+            self.reads.set(self.reads.get() + 1);
+            Ok(0)
+        }
+    }
+
+    let reads = Cell::new(0);
+    let mut stream = Stream::new(Empty { reads: &reads }, true);
+    assert!(stream
+        .pump_for(Duration::from_millis(20))
+        .unwrap()
+        .is_empty());
+    assert_eq!(reads.get(), 1);
+
+    let mut stream = Stream::new(Cursor::new(&[0x70, 0x70]), true);
+    assert_eq!(stream.pump_for(Duration::from_secs(60)).unwrap().len(), 2);
+}
+
+#[test]
+fn exception_number_above_255() {
+    let mut stream = Stream::new(
+        Cursor::new(&[
+            // Exception trace; enter 300
+            0x0e, 0x2c, 0x11, //
+            // Exception trace; return 511
+            0x0e, 0xff, 0x31,
+        ]),
+        false,
+    );
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::ExceptionTrace(et) => {
+            assert_eq!(et.number(), 300);
+            assert_eq!(et.function(), Function::Enter);
+        }
+        _ => panic!(),
+    }
+
+    match stream.next().unwrap().unwrap().unwrap() {
+        Packet::ExceptionTrace(et) => {
+            assert_eq!(et.number(), 511);
+            assert_eq!(et.function(), Function::Return);
+        }
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn trace_event() {
+    let mut stream = Stream::new(
+        Cursor::new(&[
+            // Stimulus Port Page 1
+            0x18, //
+            // Instrumentation (port 2)
+            0x12, 0x68, 0x69, //
+            // Exception trace; exit 15
+            0x0e, 0x0f, 0x20, //
+            // Periodic PC sleep
+            0x15, 0x00, //
+            // Data trace data value (comparator 1, write)
+            0x9d, 0x2a, //
+            // Event counter; CPICNT and POSTCNT wrapped
+            0x05, 0x21, //
+            // Data trace address (comparator 1)
+            0x5e, 0x34, 0x12, //
+            // Overflow
+            0x70,
+        ]),
+        false,
+    );
+
+    let mut events = vec![];
+    while let Some(packet) = stream.next().unwrap() {
+        events.push(TraceEvent::from(&packet.unwrap()));
+    }
+
+    assert_eq!(events.len(), 8);
+    assert!(events.iter().all(|event| event.offset_ns.is_none()));
+
+    assert_eq!(events[0].kind, "stimulus_port_page");
+    assert_eq!(events[0].value, Some(vec![1]));
+
+    assert_eq!(events[1].kind, "instrumentation");
+    assert_eq!(events[1].port, Some(34));
+    assert_eq!(events[1].value, Some(b"hi".to_vec()));
+    assert_eq!(events[1].text, Some("hi".to_owned()));
+
+    assert_eq!(events[2].kind, "exception_trace");
+    assert_eq!(events[2].value, Some(vec![15, 0]));
+    assert_eq!(events[2].text, Some("exit".to_owned()));
+
+    assert_eq!(events[3].kind, "pc_sample");
+    assert_eq!(events[3].pc, None);
+
+    assert_eq!(events[4].kind, "data_trace_data_value");
+    assert_eq!(events[4].comparator, Some(1));
+    assert_eq!(events[4].value, Some(vec![0x2a]));
+    assert_eq!(events[4].text, Some("write".to_owned()));
+
+    assert_eq!(events[5].kind, "event_counter");
+    assert_eq!(events[5].value, Some(vec![0x21]));
+    assert_eq!(events[5].text, Some("CPICNT,POSTCNT".to_owned()));
+
+    assert_eq!(events[6].kind, "data_trace_address");
+    assert_eq!(events[6].comparator, Some(1));
+    assert_eq!(events[6].value, Some(vec![0x34, 0x12]));
+
+    assert_eq!(events[7].kind, "overflow");
+    assert_eq!(events[7].port, None);
+    assert_eq!(events[7].value, None);
+
+    let event = TraceEvent::timestamped(&Packet::Overflow, 1_500);
+    assert_eq!(event.offset_ns, Some(1_500));
+    assert_eq!(event.kind, "overflow");
+}
+
+#[test]
+fn error_display() {
+    assert_eq!(
+        Error::ReservedHeader { byte: 4 }.to_string(),
+        "reserved header byte: 4"
+    );
+    assert_eq!(
+        Error::UnexpectedEof {
+            bytes: vec![0x02, 0x61]
+        }
+        .to_string(),
+        "unexpected EOF after 2 byte(s) of a packet"
+    );
+}
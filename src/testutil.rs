@@ -0,0 +1,239 @@
+//! Helpers for testing code that emits ITM traces
+
+use std::fmt::Write;
+
+use crate::{
+    packet::{
+        ExceptionTrace, Function, Instrumentation, LocalTimestamp, PeriodicPcSample,
+        StimulusPortPage, Synchronization, GTS1, GTS2,
+    },
+    Packet, Stream,
+};
+
+/// Asserts that `bytes` decode into exactly the `expected` packets
+///
+/// # Panics
+///
+/// Panics if decoding any packet fails or if the decoded packets differ from `expected`. In the
+/// latter case the panic message includes a per-packet diff where `-` lines are expected packets
+/// and `+` lines are the packets that were actually decoded.
+pub fn assert_decodes_to(bytes: &[u8], expected: &[Packet]) {
+    let mut stream = Stream::new(bytes, false);
+
+    let mut actual = vec![];
+    while let Some(packet) = stream.next().expect("reading from a slice can't fail") {
+        match packet {
+            Ok(packet) => actual.push(packet),
+            Err(e) => panic!("decoding error after {} packet(s): {}", actual.len(), e),
+        }
+    }
+
+    if actual == expected {
+        return;
+    }
+
+    let mut diff = String::new();
+    for i in 0..actual.len().max(expected.len()) {
+        match (expected.get(i), actual.get(i)) {
+            (Some(e), Some(a)) if e == a => writeln!(diff, "  {:?}", e),
+            (e, a) => {
+                if let Some(e) = e {
+                    writeln!(diff, "- {:?}", e).ok();
+                }
+
+                match a {
+                    Some(a) => writeln!(diff, "+ {:?}", a),
+                    None => Ok(()),
+                }
+            }
+        }
+        .ok();
+    }
+
+    panic!("decoded packets don't match the expected ones:\n{}", diff);
+}
+
+/// Synthesizes ITM byte streams, one packet at a time
+///
+/// ```
+/// use itm::{testutil::TraceBuilder, Packet, Stream};
+///
+/// let bytes = TraceBuilder::new()
+///     .sync()
+///     .instrumentation(0, b"hi")
+///     .pc_sample(None)
+///     .build();
+///
+/// let mut stream = Stream::new(&bytes[..], false);
+/// match stream.next() {
+///     Ok(Some(Ok(Packet::Synchronization(_)))) => {}
+///     _ => panic!(),
+/// }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct TraceBuilder {
+    bytes: Vec<u8>,
+}
+
+impl TraceBuilder {
+    /// Creates an empty stream
+    pub fn new() -> Self {
+        TraceBuilder::default()
+    }
+
+    /// Appends an encoded packet
+    pub fn packet(mut self, packet: Packet) -> Self {
+        self.bytes.extend(packet.encode());
+        self
+    }
+
+    /// Appends raw bytes, e.g. to craft malformed packets
+    pub fn bytes(mut self, bytes: &[u8]) -> Self {
+        self.bytes.extend_from_slice(bytes);
+        self
+    }
+
+    /// Appends a minimal Synchronization packet
+    pub fn sync(self) -> Self {
+        self.packet(Packet::Synchronization(Synchronization { len: 6 }))
+    }
+
+    /// Appends an Overflow packet
+    pub fn overflow(self) -> Self {
+        self.packet(Packet::Overflow)
+    }
+
+    /// Appends an Instrumentation packet
+    ///
+    /// # Panics
+    ///
+    /// This method panics if `port` is greater than 31 or if `payload` is not 1, 2 or 4 bytes
+    /// long
+    pub fn instrumentation(self, port: u8, payload: &[u8]) -> Self {
+        assert!(port < 32, "`port` must be in the range 0..=31");
+        assert!(
+            [1, 2, 4].contains(&payload.len()),
+            "`payload` must be 1, 2 or 4 bytes long"
+        );
+
+        let mut buffer = [0; 4];
+        buffer[..payload.len()].copy_from_slice(payload);
+
+        self.packet(Packet::Instrumentation(Instrumentation {
+            buffer,
+            page: 0,
+            port,
+            size: payload.len() as u8,
+        }))
+    }
+
+    /// Appends a Stimulus Port Page packet
+    ///
+    /// # Panics
+    ///
+    /// This method panics if `page` is greater than 7
+    pub fn page(self, page: u8) -> Self {
+        assert!(page < 8, "`page` must be in the range 0..=7");
+
+        self.packet(Packet::StimulusPortPage(StimulusPortPage { page }))
+    }
+
+    /// Appends a Local timestamp packet (format 1) with the given TC (relation) bits
+    ///
+    /// # Panics
+    ///
+    /// This method panics if `delta` doesn't fit in 28 bits or if `tc` is greater than 3
+    pub fn lts1(self, delta: u32, tc: u8) -> Self {
+        assert!(delta < 1 << 28, "`delta` must fit in 28 bits");
+        assert!(tc < 4, "`tc` must be in the range 0..=3");
+
+        self.packet(Packet::LocalTimestamp(LocalTimestamp {
+            delta,
+            tc,
+            len: 1 + groups(u64::from(delta)),
+        }))
+    }
+
+    /// Appends a Local timestamp packet (format 2)
+    ///
+    /// # Panics
+    ///
+    /// This method panics if `delta` is not in the range `1..=6`
+    #[allow(clippy::manual_range_contains)]
+    pub fn lts2(self, delta: u8) -> Self {
+        assert!(
+            delta >= 1 && delta <= 6,
+            "`delta` must be in the range 1..=6"
+        );
+
+        self.packet(Packet::LocalTimestamp(LocalTimestamp {
+            delta: u32::from(delta),
+            tc: 0,
+            len: 1,
+        }))
+    }
+
+    /// Appends a Global timestamp packet (format 1) without the clock change and wrap flags
+    ///
+    /// # Panics
+    ///
+    /// This method panics if `bits` doesn't fit in 26 bits
+    pub fn gts1(self, bits: u32) -> Self {
+        assert!(bits < 1 << 26, "`bits` must fit in 26 bits");
+
+        self.packet(Packet::GTS1(GTS1 {
+            bits,
+            clk_ch: false,
+            len: 1 + groups(u64::from(bits)),
+            wrap: false,
+        }))
+    }
+
+    /// Appends a Global timestamp packet (format 2)
+    ///
+    /// # Panics
+    ///
+    /// This method panics if `bits` doesn't fit in 22 bits (48-bit timestamps) or 38 bits
+    /// (64-bit timestamps)
+    pub fn gts2(self, bits: u64, b64: bool) -> Self {
+        let (width, len) = if b64 { (38, 7) } else { (22, 5) };
+        assert!(bits < 1 << width, "`bits` doesn't fit in the timestamp");
+
+        self.packet(Packet::GTS2(GTS2 {
+            approximate: false,
+            bits,
+            b64,
+            len,
+        }))
+    }
+
+    /// Appends an Exception trace packet
+    ///
+    /// # Panics
+    ///
+    /// This method panics if `number` doesn't fit in 9 bits
+    pub fn exception(self, number: u16, function: Function) -> Self {
+        assert!(number < 1 << 9, "`number` must fit in 9 bits");
+
+        self.packet(Packet::ExceptionTrace(ExceptionTrace { function, number }))
+    }
+
+    /// Appends a Periodic PC sample packet; `None` encodes a sleeping core
+    pub fn pc_sample(self, pc: Option<u32>) -> Self {
+        self.packet(Packet::PeriodicPcSample(PeriodicPcSample { pc }))
+    }
+
+    /// Returns the encoded stream
+    pub fn build(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+// number of 7-bit groups needed to encode `value`
+fn groups(value: u64) -> u8 {
+    let mut groups = 1;
+    while value >> (7 * groups) != 0 {
+        groups += 1;
+    }
+    groups
+}